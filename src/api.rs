@@ -1,25 +1,26 @@
 use crate::mem::Address;
-use smallvec::SmallVec;
+use std::cell::Cell;
 pub unsafe trait Trace: Finalizer {
     fn mark(&self);
     fn unmark(&self);
-    fn references(&self) -> SmallVec<[*const dyn HeapTrait; 64]>;
+    /// Passes each GC pointer of the value to `tracer`.
+    fn trace(&self, tracer: &mut Tracer);
+    /// Name of the type, used in diagnostics.
+    fn type_name(&self) -> &'static str;
 }
 
-#[derive(Default)]
-pub struct Tracer {
-    stack: SmallVec<[*const dyn HeapTrait; 64]>,
+/// Visitor passed to `Traceable::trace_with`, each traced pointer is handed straight to the collector (e.g. pushed
+/// to worklist of GC worker) so no buffer of references is built.
+pub struct Tracer<'a> {
+    visit: &'a mut dyn FnMut(*const dyn HeapTrait),
 }
-impl Tracer {
-    pub fn for_each(&mut self, mut f: impl FnMut(*const dyn HeapTrait)) {
-        while let Some(item) = self.stack.pop() {
-            f(item);
-        }
-        //self.stack.into_iter().for_each(|x| f(*x));
+impl<'a> Tracer<'a> {
+    pub fn new(visit: &'a mut dyn FnMut(*const dyn HeapTrait)) -> Self {
+        Self { visit }
     }
 
     pub fn trace(&mut self, item: *const dyn HeapTrait) {
-        self.stack.push(item);
+        (self.visit)(item);
     }
 }
 
@@ -32,20 +33,22 @@ where
 
 unsafe impl<T: Traceable> Trace for T {
     fn mark(&self) {
-        let mut tracer = Tracer::default();
-        self.trace_with(&mut tracer);
-        tracer.for_each(|pointer| unsafe { (*pointer).mark() });
+        self.trace_with(&mut Tracer::new(&mut |pointer| unsafe {
+            (*pointer).mark()
+        }));
     }
     fn unmark(&self) {
-        let mut tracer = Tracer::default();
-        self.trace_with(&mut tracer);
-        tracer.for_each(|pointer| unsafe { (*pointer).unmark() });
+        self.trace_with(&mut Tracer::new(&mut |pointer| unsafe {
+            (*pointer).unmark()
+        }));
+    }
+
+    fn trace(&self, tracer: &mut Tracer) {
+        self.trace_with(tracer);
     }
 
-    fn references(&self) -> SmallVec<[*const dyn HeapTrait; 64]> {
-        let mut tracer = Tracer::default();
-        self.trace_with(&mut tracer);
-        tracer.stack
+    fn type_name(&self) -> &'static str {
+        std::any::type_name::<T>()
     }
 }
 
@@ -58,6 +61,8 @@ pub unsafe trait HeapTrait {
     fn copy_to(&self, addr: Address);
     fn addr(&self) -> Address;
     fn inner(&self) -> *mut crate::heap::HeapInner<dyn Trace>;
+    /// Points this pointer to `to`, the new location of its object, unless it no longer points to `from`.
+    fn relocate(&self, from: Address, to: Address);
 
     fn is_marked(&self) -> bool;
 }
@@ -171,7 +176,7 @@ where
     Self: HeapTrait,
 {
     fn is_rooted(&self) -> bool;
-    fn references(&self) -> SmallVec<[*const dyn HeapTrait; 64]>;
+    fn trace(&self, tracer: &mut Tracer);
 }
 
 pub struct Rooted<T: Trace + ?Sized> {
@@ -187,28 +192,51 @@ impl<T: Trace + Sized> Rooted<T> {
     }
     pub fn get(&self) -> &T {
         unsafe {
-            &(&*crate::heap::read_barrier_impl(self.inner().inner as *const _ as *mut _)).value
+            &(&*crate::heap::read_barrier_impl(self.inner().inner.get() as *const _ as *mut _))
+                .value
         }
         //unsafe { &(&*self.inner().inner).value }
     }
-    pub fn get_mut(&self) -> &mut T {
-        unsafe {
-            &mut (&mut *crate::heap::read_barrier_impl(self.inner().inner as *const _ as *mut _))
-                .value
-        }
+    pub fn get_mut(&self) -> &mut T
+    where
+        T: 'static,
+    {
+        unsafe { &mut (*crate::heap::write_access(self.inner().inner.get())).value }
+    }
+}
+
+impl<T: Trace + Sized + 'static> Rooted<T> {
+    /// Pins object, it is not moved by collector until returned guard is dropped. Young objects are promoted
+    /// first, so pinning runs minor collection if object is in nursery.
+    pub fn pin(&self) -> Pinned<T> {
+        let rooted = crate::threads::mt_root(Handle::from(self));
+        crate::threads::THREAD.with(|thread| {
+            thread.borrow().with_heap(|heap| {
+                let object = heap.pin(unsafe { &*rooted.inner });
+                Pinned {
+                    object: object as *mut crate::heap::HeapInner<T>,
+                    heap: heap.clone(),
+                    _rooted: rooted,
+                }
+            })
+        })
     }
 }
 
 pub(crate) struct RootedInner<T: Trace + ?Sized> {
-    pub(crate) rooted: bool,
-    pub(crate) inner: *mut crate::heap::HeapInner<T>,
+    /// Cleared when `Rooted` is dropped, atomic since roots of detached threads are scanned while their
+    /// `Rooted`s might be dropped.
+    pub(crate) rooted: std::sync::atomic::AtomicBool,
+    pub(crate) inner: Cell<*mut crate::heap::HeapInner<T>>,
 }
 impl<T: Trace + ?Sized> Drop for Rooted<T> {
     fn drop(&mut self) {
         unsafe {
             debug_assert!(!self.inner.is_null());
-            let inner = &mut *self.inner;
-            inner.rooted = false;
+            let inner = &*self.inner;
+            inner
+                .rooted
+                .store(false, std::sync::atomic::Ordering::Release);
         }
     }
 }
@@ -216,53 +244,58 @@ impl<T: Trace + ?Sized> Drop for Rooted<T> {
 unsafe impl<T: Trace + Sized + 'static> HeapTrait for RootedInner<T> {
     fn mark(&self) {
         unsafe {
-            (&mut *self.inner).mark(true);
+            (&mut *self.inner.get()).mark(true);
         }
     }
 
     fn unmark(&self) {
         unsafe {
-            (&mut *self.inner).mark(false);
+            (&mut *self.inner.get()).mark(false);
         }
     }
     fn get_fwd(&self) -> Address {
-        unsafe { (&*self.inner).fwdptr() }
+        unsafe { (&*self.inner.get()).fwdptr() }
     }
 
     fn set_fwd(&self, fwd: Address) {
         unsafe {
-            (&mut *self.inner).set_fwdptr(fwd);
+            (&mut *self.inner.get()).set_fwdptr(fwd);
         }
     }
 
     fn copy_to(&self, addr: Address) {
-        debug_assert!(addr.is_non_null() && !self.inner.is_null());
+        debug_assert!(addr.is_non_null() && !self.inner.get().is_null());
         unsafe {
             std::ptr::copy(
-                self.inner as *const u8,
+                self.inner.get() as *const u8,
                 addr.to_mut_ptr(),
-                std::mem::size_of_val(&*self.inner),
+                std::mem::size_of_val(&*self.inner.get()),
             )
         }
     }
 
     fn addr(&self) -> Address {
-        Address::from_ptr(self.inner as *const u8)
+        Address::from_ptr(self.inner.get() as *const u8)
     }
     fn is_marked(&self) -> bool {
-        unsafe { (&*self.inner).is_marked() }
+        unsafe { (&*self.inner.get()).is_marked() }
     }
     fn inner(&self) -> *mut crate::heap::HeapInner<dyn Trace> {
-        self.inner
+        self.inner.get()
+    }
+    fn relocate(&self, from: Address, to: Address) {
+        if self.inner.get() as *mut u8 == from.to_mut_ptr() {
+            self.inner.set(to.to_mut_ptr());
+        }
     }
 }
 
 impl<T: Trace + Sized + 'static> RootedTrait for RootedInner<T> {
     fn is_rooted(&self) -> bool {
-        self.rooted
+        self.rooted.load(std::sync::atomic::Ordering::Acquire)
     }
-    fn references(&self) -> SmallVec<[*const dyn HeapTrait; 64]> {
-        unsafe { (&*self.inner).value.references() }
+    fn trace(&self, tracer: &mut Tracer) {
+        unsafe { (&*self.inner.get()).value.trace(tracer) }
     }
 }
 
@@ -270,13 +303,14 @@ impl<T: Trace + Sized + 'static> RootedTrait for RootedInner<T> {
 ///
 /// GC thing pointers on the heap must be wrapped in a `Handle<T>`
 pub struct Handle<T: Trace + ?Sized> {
+    /// Rewritten by collector through `Handle::slot` when object is moved.
     pub(crate) inner: *mut crate::heap::HeapInner<T>,
 }
 impl<T: Trace + ?Sized> From<Rooted<T>> for Handle<T> {
     fn from(x: Rooted<T>) -> Self {
         unsafe {
             Self {
-                inner: (*x.inner).inner,
+                inner: (*x.inner).inner.get(),
             }
         }
     }
@@ -286,7 +320,7 @@ impl<T: Trace + ?Sized> From<&Rooted<T>> for Handle<T> {
     fn from(x: &Rooted<T>) -> Self {
         unsafe {
             Self {
-                inner: (*x.inner).inner,
+                inner: (*x.inner).inner.get(),
             }
         }
     }
@@ -295,12 +329,19 @@ impl<T: Trace + ?Sized> From<&Rooted<T>> for Handle<T> {
 impl<T: Trace + Sized> Handle<T> {
     pub fn get(&self) -> &T {
         unsafe {
-            debug_assert!(!self.inner.is_null());
-            let src = crate::heap::read_barrier_impl(self.inner as *const _ as *mut _);
+            let inner = self.slot().load(std::sync::atomic::Ordering::Acquire);
+            debug_assert!(!inner.is_null());
+            let src = crate::heap::read_barrier_impl(inner);
             &(&*src).value
         }
     }
 
+    /// Pointer of this handle as seen by collector. `Handle` is `Copy`, so its pointer can't be kept in a `Cell`,
+    /// collector rewrites it atomically through its address like it writes object headers.
+    fn slot(&self) -> &std::sync::atomic::AtomicPtr<crate::heap::HeapInner<T>> {
+        unsafe { &*Address::from_ptr(&self.inner).to_ptr() }
+    }
+
     /// Returns mutable reference to rooted value
     ///
     /// # Safety
@@ -309,10 +350,14 @@ impl<T: Trace + Sized> Handle<T> {
     /// If you want to be 100% sure that you don't have two or more mutable references at the same time please use `Heap<RefCell<T>>`
     ///
     ///
-    pub fn get_mut(&mut self) -> &mut T {
+    pub fn get_mut(&mut self) -> &mut T
+    where
+        T: 'static,
+    {
         unsafe {
-            debug_assert!(!self.inner.is_null());
-            let src = crate::heap::read_barrier_impl(self.inner as *const _ as *mut _);
+            let inner = self.slot().load(std::sync::atomic::Ordering::Acquire);
+            debug_assert!(!inner.is_null());
+            let src = crate::heap::write_access(inner);
             &mut (&mut *src).value
         }
     }
@@ -356,7 +401,16 @@ unsafe impl<T: Trace + Sized + 'static> HeapTrait for Handle<T> {
         unsafe { (&*self.inner).is_marked() }
     }
     fn inner(&self) -> *mut crate::heap::HeapInner<dyn Trace> {
-        self.inner
+        self.slot().load(std::sync::atomic::Ordering::Acquire)
+    }
+    fn relocate(&self, from: Address, to: Address) {
+        // GC worker relocates slots while mutators run, store done by mutator in the meantime is kept.
+        let _ = self.slot().compare_exchange(
+            from.to_mut_ptr(),
+            to.to_mut_ptr(),
+            std::sync::atomic::Ordering::AcqRel,
+            std::sync::atomic::Ordering::Relaxed,
+        );
     }
 }
 impl<T: Trace> Copy for Handle<T> {}
@@ -366,6 +420,112 @@ impl<T: Trace> Clone for Handle<T> {
     }
 }
 
+/// Slot of weak reference, holds address of target object or zero once target is dead.
+pub(crate) struct WeakSlot {
+    pub(crate) target: std::sync::atomic::AtomicUsize,
+}
+
+/// Weak reference to GC object, doesn't keep its target alive.
+///
+/// Collector clears weak reference when its target dies and updates it when target is moved.
+pub struct Weak<T: Trace> {
+    slot: std::sync::Arc<WeakSlot>,
+    marker: std::marker::PhantomData<T>,
+}
+
+impl<T: Trace + Sized + 'static> Weak<T> {
+    /// Creates weak reference to `handle` target in heap the current thread is attached to.
+    pub fn new(handle: Handle<T>) -> Self {
+        let target = unsafe { crate::heap::read_barrier_impl(handle.inner) };
+        let slot = crate::threads::THREAD.with(|thread| {
+            thread
+                .borrow()
+                .with_heap(|heap| heap.register_weak(Address::from_ptr(target as *const u8)))
+        });
+        Self {
+            slot,
+            marker: std::marker::PhantomData,
+        }
+    }
+
+    /// Roots target object, returns `None` if it is dead.
+    pub fn upgrade(&self) -> Option<Rooted<T>> {
+        let target = self.slot.target.load(std::sync::atomic::Ordering::Acquire);
+        if target == 0 {
+            return None;
+        }
+        Some(crate::threads::mt_root(Handle {
+            inner: target as *mut crate::heap::HeapInner<T>,
+        }))
+    }
+
+    pub fn is_alive(&self) -> bool {
+        self.slot.target.load(std::sync::atomic::Ordering::Acquire) != 0
+    }
+}
+
+impl<T: Trace + Sized + 'static> From<&Rooted<T>> for Weak<T> {
+    fn from(x: &Rooted<T>) -> Self {
+        Self::new(Handle::from(x))
+    }
+}
+
+impl<T: Trace + Sized + 'static> From<Handle<T>> for Weak<T> {
+    fn from(x: Handle<T>) -> Self {
+        Self::new(x)
+    }
+}
+
+impl<T: Trace> Clone for Weak<T> {
+    fn clone(&self) -> Self {
+        Self {
+            slot: self.slot.clone(),
+            marker: std::marker::PhantomData,
+        }
+    }
+}
+
+// Weak references are not traced, so they don't keep their targets alive.
+impl<T: Trace> Traceable for Weak<T> {}
+impl<T: Trace> Finalizer for Weak<T> {}
+
+/// Guard returned by `Rooted::pin`, keeps object alive and at the same address so it can be passed to native code.
+pub struct Pinned<T: Trace + 'static> {
+    object: *mut crate::heap::HeapInner<T>,
+    heap: std::sync::Arc<crate::heap::GlobalHeap>,
+    _rooted: Rooted<T>,
+}
+
+impl<T: Trace + 'static> Pinned<T> {
+    /// Address of pinned value, valid until guard is dropped.
+    pub fn as_ptr(&self) -> *const T {
+        unsafe { &(*self.object).value }
+    }
+
+    pub fn as_mut_ptr(&self) -> *mut T {
+        unsafe { &mut (*self.object).value }
+    }
+}
+
+impl<T: Trace + 'static> Deref for Pinned<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.as_ptr() }
+    }
+}
+
+impl<T: Trace + 'static> DerefMut for Pinned<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.as_mut_ptr() }
+    }
+}
+
+impl<T: Trace + 'static> Drop for Pinned<T> {
+    fn drop(&mut self) {
+        self.heap.unpin(self.object);
+    }
+}
+
 use std::cmp;
 
 impl<T: Trace + PartialOrd> PartialOrd for Handle<T> {
@@ -452,7 +612,7 @@ impl<T: Trace> Finalizer for Handle<T> {
     fn finalize(&mut self) {}
 }
 
-impl<T: Traceable> Finalizer for Rooted<T> {
+impl<T: Traceable + 'static> Finalizer for Rooted<T> {
     fn finalize(&mut self) {
         self.get_mut().finalize();
     }
@@ -467,7 +627,7 @@ impl<T: Traceable> Deref for Rooted<T> {
     }
 }
 
-impl<T: Traceable> DerefMut for Rooted<T> {
+impl<T: Traceable + 'static> DerefMut for Rooted<T> {
     fn deref_mut(&mut self) -> &mut T {
         self.get_mut()
     }
@@ -480,7 +640,7 @@ impl<T: Traceable> Deref for Handle<T> {
     }
 }
 
-impl<T: Traceable> DerefMut for Handle<T> {
+impl<T: Traceable + 'static> DerefMut for Handle<T> {
     fn deref_mut(&mut self) -> &mut T {
         self.get_mut()
     }
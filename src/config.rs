@@ -0,0 +1,135 @@
+/// Policy that decides when allocation starts new collection cycle.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum GcTrigger {
+    /// Collection cycles are started only by `GlobalHeap::collect`.
+    Manual,
+    /// Start collection cycle after this amount of bytes was allocated since previous cycle.
+    Fixed(usize),
+    /// Start collection cycle once heap is this many times bigger than the amount of bytes that survived previous cycle.
+    Growth(usize),
+}
+
+/// Forced collections used to make rooting bugs reproducible.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum GcStress {
+    Off,
+    /// Run full synchronous collection after every allocation.
+    EveryAllocation,
+    /// Run full synchronous collection after every allocation and every `mt_root`.
+    EveryAllocationAndRoot,
+    /// Run full synchronous collection after every N-th allocation.
+    EveryNthAllocation(usize),
+    /// Run full synchronous collection after every `mt_root` only, allocations don't collect.
+    EveryRoot,
+    /// Run full synchronous collection at every `safepoint!` poll. Polls of all threads take the slow path
+    /// while heap in this mode exists.
+    EverySafepoint,
+}
+
+/// What heap does when mutators don't reach safepoint within `HeapConfig::safepoint_timeout`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SafepointTimeoutPolicy {
+    /// Log threads that are not at safepoint and keep waiting, they are logged again after each timeout.
+    Log,
+    /// Resume threads and panic in the mutator that stops the world. Pauses of the collector thread are logged as
+    /// with `Log`, since it can't unwind in the middle of collection cycle.
+    Panic,
+    /// Print threads that are not at safepoint to stderr and abort the process.
+    Abort,
+}
+
+/// Heap configuration, use `HeapConfig::default()` and struct update syntax to change only some of the values:
+/// ```rust
+/// use cgc::config::*;
+/// let config = HeapConfig {
+///     trigger: GcTrigger::Manual,
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Clone, Debug)]
+pub struct HeapConfig {
+    /// Size of heap block, rounded up to OS page size.
+    pub page_size: usize,
+    /// Amount of bytes that can be allocated before the first collection cycle is started.
+    pub initial_heap_size: usize,
+    /// Maximum amount of memory committed by heap, allocation that needs more memory runs emergency collection
+    /// and fails if it doesn't free enough memory. Collection threshold never grows over this size.
+    /// Defaults to the size of physical memory.
+    pub max_heap_size: usize,
+    /// Number of threads that copy objects, collector thread is one of them.
+    pub collector_threads: usize,
+    /// Size of thread local allocation buffers, mutators allocate objects smaller than half of it without locking heap.
+    pub tlab_size: usize,
+    /// Objects of this size or bigger are allocated in large object space and are never copied.
+    pub large_object_size: usize,
+    pub trigger: GcTrigger,
+    /// Size of young generation, objects are allocated in nursery and promoted to old space after they survive
+    /// `promotion_age` minor collections. Zero disables generational collection.
+    pub nursery_size: usize,
+    pub promotion_age: u8,
+    /// Number of recent cycles kept in `HeapStats::history`.
+    pub stats_history: usize,
+    /// Run cleanups of `FinalizationRegistry`s and finalizers of collected objects on a dedicated thread. If
+    /// disabled, they are run only by `GlobalHeap::run_cleanups`.
+    pub finalizer_thread: bool,
+    /// Run `GlobalHeap::verify` before and after every collection cycle and panic if heap is broken. Slow, meant
+    /// for debugging rooting and barrier bugs.
+    pub verify: bool,
+    /// Detect stores done without `write_barrier` while objects are copied, see `GlobalHeap::missing_barriers`.
+    /// Slow, meant for tests and debugging.
+    pub check_barriers: bool,
+    /// Stress mode, evacuated memory is filled with `space::POISON` after each cycle while it is enabled, so
+    /// unrooted `Handle`s kept across safepoints read garbage right away.
+    pub stress: GcStress,
+    /// Fill evacuated from-space and nursery pages with `space::POISON` and protect them until they are reused,
+    /// access through stale pointer crashes right away and on unix reports the page and the cycle it was
+    /// evacuated in.
+    pub protect_evacuated: bool,
+    /// Time mutators have to reach safepoint once the world is being stopped, after that threads that are still
+    /// running are reported according to `safepoint_timeout_policy`. `None` waits silently.
+    pub safepoint_timeout: Option<time::Duration>,
+    pub safepoint_timeout_policy: SafepointTimeoutPolicy,
+}
+
+impl HeapConfig {
+    pub fn generational(&self) -> bool {
+        self.nursery_size != 0
+    }
+
+    /// Returns amount of allocated bytes after which collection cycle should be started,
+    /// `live` is the amount of bytes that survived previous cycle.
+    pub fn size_limit(&self, live: usize) -> usize {
+        match self.trigger {
+            GcTrigger::Manual => usize::MAX,
+            GcTrigger::Fixed(bytes) => live.saturating_add(bytes).min(self.max_heap_size),
+            GcTrigger::Growth(factor) => live
+                .saturating_mul(factor)
+                .max(self.initial_heap_size)
+                .min(self.max_heap_size),
+        }
+    }
+}
+
+impl Default for HeapConfig {
+    fn default() -> Self {
+        Self {
+            page_size: 32 * 1024,
+            initial_heap_size: 64 * 1024,
+            max_heap_size: crate::mem::memory_limit(),
+            collector_threads: 1,
+            tlab_size: 4 * 1024,
+            large_object_size: 8 * 1024,
+            trigger: GcTrigger::Growth(2),
+            nursery_size: 0,
+            promotion_age: 2,
+            stats_history: 32,
+            finalizer_thread: true,
+            verify: false,
+            check_barriers: false,
+            stress: GcStress::Off,
+            protect_evacuated: false,
+            safepoint_timeout: Some(time::Duration::seconds(1)),
+            safepoint_timeout_policy: SafepointTimeoutPolicy::Log,
+        }
+    }
+}
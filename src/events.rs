@@ -0,0 +1,104 @@
+use crate::stats::CycleStats;
+use std::sync::Arc;
+
+/// Point of collection cycle at which callbacks registered with `GlobalHeap::add_callback` are called.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum GcEvent {
+    /// World is stopped and roots are about to be scanned.
+    RootScan,
+    /// Collector starts copying objects, mutators are running. Not sent for minor collections.
+    CopyStart,
+    /// World is stopped, objects were moved and roots point to new locations.
+    Flip,
+    /// Cycle is finished, statistics are final.
+    CycleEnd,
+}
+
+/// Identifies registered callback, used to remove it.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct CallbackId(pub(crate) usize);
+
+pub(crate) type Callback = Arc<dyn Fn(&CycleStats) + Send + Sync>;
+
+/// Callbacks registered in heap.
+#[derive(Default)]
+pub(crate) struct Callbacks {
+    next_id: usize,
+    callbacks: Vec<(CallbackId, GcEvent, Callback)>,
+}
+
+impl Callbacks {
+    pub fn add(&mut self, event: GcEvent, callback: Callback) -> CallbackId {
+        let id = CallbackId(self.next_id);
+        self.next_id += 1;
+        self.callbacks.push((id, event, callback));
+        id
+    }
+
+    pub fn remove(&mut self, id: CallbackId) -> bool {
+        let len = self.callbacks.len();
+        self.callbacks.retain(|(callback, _, _)| *callback != id);
+        self.callbacks.len() != len
+    }
+
+    /// Returns callbacks registered for `event`, they are called after registry is unlocked so callbacks can
+    /// register other callbacks.
+    pub fn get(&self, event: GcEvent) -> Vec<Callback> {
+        self.callbacks
+            .iter()
+            .filter(|(_, kind, _)| *kind == event)
+            .map(|(_, _, callback)| callback.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::heap::testing::*;
+    use crate::heap::GlobalHeap;
+    use crate::threads::mt_alloc;
+
+    #[test]
+    fn test_callbacks() {
+        let heap = GlobalHeap::new(manual());
+        let events = Arc::new(parking_lot::Mutex::new(vec![]));
+        let ids = [
+            GcEvent::RootScan,
+            GcEvent::CopyStart,
+            GcEvent::Flip,
+            GcEvent::CycleEnd,
+        ]
+        .iter()
+        .map(|event| {
+            let events = events.clone();
+            let event = *event;
+            heap.add_callback(event, move |cycle| {
+                events
+                    .lock()
+                    .push((event, cycle.epoch, cycle.surviving_objects))
+            })
+        })
+        .collect::<Vec<_>>();
+        heap.attach_current_thread();
+        let _value = mt_alloc(42, false);
+        heap.collect_sync();
+        assert_eq!(
+            *events.lock(),
+            vec![
+                (GcEvent::RootScan, 0, 0),
+                (GcEvent::CopyStart, 0, 0),
+                (GcEvent::Flip, 0, 1),
+                (GcEvent::CycleEnd, 1, 1)
+            ]
+        );
+
+        for id in ids.iter().copied() {
+            assert!(heap.remove_callback(id));
+        }
+        assert!(!heap.remove_callback(ids[0]));
+        heap.collect_sync();
+        assert_eq!(events.lock().len(), 4);
+        finish(&heap);
+    }
+}
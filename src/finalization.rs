@@ -0,0 +1,341 @@
+use crate::api::*;
+use crate::heap::*;
+use crate::mem::Address;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Cleanup of one registration with its held value.
+pub(crate) type Cleanup = Box<dyn FnOnce() + Send>;
+
+/// Identifies registration in `FinalizationRegistry`, used to unregister it.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct RegistrationToken(pub(crate) usize);
+
+struct Registration {
+    token: RegistrationToken,
+    /// Address of target object.
+    target: usize,
+    cleanup: Cleanup,
+}
+
+/// Objects registered in `FinalizationRegistry`s of heap.
+#[derive(Default)]
+pub(crate) struct Registrations {
+    next_token: usize,
+    registrations: Vec<Registration>,
+}
+
+impl Registrations {
+    pub fn add(&mut self, target: Address, cleanup: Cleanup) -> RegistrationToken {
+        let token = RegistrationToken(self.next_token);
+        self.next_token += 1;
+        self.registrations.push(Registration {
+            token,
+            target: target.to_usize(),
+            cleanup,
+        });
+        token
+    }
+
+    pub fn remove(&mut self, token: RegistrationToken) -> bool {
+        let len = self.registrations.len();
+        self.registrations
+            .retain(|registration| registration.token != token);
+        self.registrations.len() != len
+    }
+
+    /// Updates targets to their new locations returned by `update`, returns cleanups of registrations for which
+    /// it returns `None`.
+    pub fn update(
+        &mut self,
+        mut update: impl FnMut(&HeapInner<u8>) -> Option<Address>,
+    ) -> Vec<Cleanup> {
+        let mut dead = vec![];
+        for registration in std::mem::take(&mut self.registrations) {
+            // header has the same layout for all objects.
+            let target = unsafe { &*(registration.target as *const HeapInner<u8>) };
+            match update(target) {
+                Some(address) => self.registrations.push(Registration {
+                    target: address.to_usize(),
+                    ..registration
+                }),
+                None => dead.push(registration.cleanup),
+            }
+        }
+        dead
+    }
+
+    /// Removes all registrations and returns their cleanups.
+    pub fn drain(&mut self) -> Vec<Cleanup> {
+        self.registrations
+            .drain(..)
+            .map(|registration| registration.cleanup)
+            .collect()
+    }
+}
+
+/// Cleanups and finalizers of collected objects waiting to be run by finalizer thread or `GlobalHeap::run_cleanups`.
+#[derive(Default)]
+pub(crate) struct CleanupQueue {
+    pending: parking_lot::Mutex<Pending>,
+    cond: parking_lot::Condvar,
+    stopped: AtomicBool,
+}
+
+#[derive(Default)]
+struct Pending {
+    cleanups: VecDeque<Cleanup>,
+    /// Number of cleanups that are queued or running.
+    unfinished: usize,
+}
+
+impl CleanupQueue {
+    pub fn push(&self, cleanups: Vec<Cleanup>) {
+        if cleanups.is_empty() {
+            return;
+        }
+        let mut pending = self.pending.lock();
+        pending.unfinished += cleanups.len();
+        pending.cleanups.extend(cleanups);
+        self.cond.notify_all();
+    }
+
+    /// Runs pending cleanups on current thread, returns number of cleanups that were run.
+    pub fn run_pending(&self) -> usize {
+        let mut count = 0;
+        loop {
+            // cleanups are run with queue unlocked so collector can add new ones.
+            let cleanup = match self.pending.lock().cleanups.pop_front() {
+                Some(cleanup) => cleanup,
+                None => return count,
+            };
+            cleanup();
+            count += 1;
+            let mut pending = self.pending.lock();
+            pending.unfinished -= 1;
+            if pending.unfinished == 0 {
+                self.cond.notify_all();
+            }
+        }
+    }
+
+    /// Body of finalizer thread, runs cleanups as they come until queue is stopped and empty.
+    pub fn run(&self) {
+        loop {
+            self.run_pending();
+            let mut pending = self.pending.lock();
+            while pending.cleanups.is_empty() {
+                if self.stopped.load(Ordering::Acquire) {
+                    return;
+                }
+                self.cond.wait(&mut pending);
+            }
+        }
+    }
+
+    /// Waits until cleanups queued so far are run by other thread.
+    pub fn wait(&self) {
+        let mut pending = self.pending.lock();
+        while pending.unfinished != 0 {
+            self.cond.wait(&mut pending);
+        }
+    }
+
+    pub fn stop(&self) {
+        let _pending = self.pending.lock();
+        self.stopped.store(true, Ordering::Release);
+        self.cond.notify_all();
+    }
+}
+
+/// Runs cleanup callback after registered objects are collected, modelled after JavaScript `FinalizationRegistry`.
+///
+/// Callbacks are never called by collector, they run on finalizer thread of heap or, if
+/// `HeapConfig::finalizer_thread` is disabled, on a thread that calls `GlobalHeap::run_cleanups`.
+/// Callback gets held value of registration and can't access the collected object.
+pub struct FinalizationRegistry<H: Send + 'static> {
+    cleanup: Arc<dyn Fn(H) + Send + Sync>,
+    heap: Arc<GlobalHeap>,
+}
+
+impl<H: Send + 'static> FinalizationRegistry<H> {
+    /// Creates registry in heap the current thread is attached to.
+    pub fn new(cleanup: impl Fn(H) + Send + Sync + 'static) -> Self {
+        let heap =
+            crate::threads::THREAD.with(|thread| thread.borrow().with_heap(|heap| heap.clone()));
+        Self {
+            cleanup: Arc::new(cleanup),
+            heap,
+        }
+    }
+
+    /// Registers `target`, once it is collected cleanup callback is called with `held`.
+    ///
+    /// Registration outlives the registry, pending cleanups are run even if registry was dropped.
+    pub fn register<T: Trace + 'static>(&self, target: Handle<T>, held: H) -> RegistrationToken {
+        let target = unsafe { read_barrier_impl(target.inner) };
+        let cleanup = self.cleanup.clone();
+        self.heap.register_cleanup(
+            Address::from_ptr(target as *const u8),
+            Box::new(move || cleanup(held)),
+        )
+    }
+
+    /// Removes registration, returns false if its target was already collected or it was unregistered before.
+    pub fn unregister(&self, token: RegistrationToken) -> bool {
+        self.heap.unregister_cleanup(token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::HeapConfig;
+    use crate::heap::testing::*;
+    use crate::threads::mt_alloc;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn test_finalization_registry() {
+        let heap = attached(manual());
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let sender = parking_lot::Mutex::new(sender);
+        let registry = FinalizationRegistry::new(move |held: &'static str| {
+            sender
+                .lock()
+                .send((held, std::thread::current().id()))
+                .unwrap();
+        });
+        let live = mt_alloc(1, false);
+        registry.register(Handle::from(&live), "live");
+        registry.register(Handle::from(mt_alloc(2, false)), "dead");
+        let token = registry.register(Handle::from(mt_alloc(3, false)), "unregistered");
+        assert!(registry.unregister(token));
+        assert!(!registry.unregister(token));
+
+        heap.collect_sync();
+        let timeout = std::time::Duration::from_secs(5);
+        let (held, thread) = receiver.recv_timeout(timeout).unwrap();
+        assert_eq!(held, "dead");
+        assert_ne!(thread, std::thread::current().id());
+        heap.collect_sync();
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(*live, 1);
+        drop(registry);
+        finish(&heap);
+        assert_eq!(receiver.recv_timeout(timeout).unwrap().0, "live");
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_run_cleanups() {
+        let heap = attached(HeapConfig {
+            nursery_size: 64 * 1024,
+            finalizer_thread: false,
+            ..manual()
+        });
+        let count = Arc::new(AtomicUsize::new(0));
+        let registry = {
+            let count = count.clone();
+            FinalizationRegistry::new(move |held: usize| {
+                count.fetch_add(held, Ordering::Relaxed);
+            })
+        };
+        for i in 1..=3 {
+            registry.register(Handle::from(mt_alloc(i, false)), i);
+        }
+        heap.minor_collect();
+        assert_eq!(count.load(Ordering::Relaxed), 0);
+        assert_eq!(heap.run_cleanups(), 3);
+        assert_eq!(count.load(Ordering::Relaxed), 6);
+        assert_eq!(heap.run_cleanups(), 0);
+        finish(&heap);
+    }
+
+    #[test]
+    fn test_allocation_doesnt_wait_for_cleanups() {
+        let heap = attached(generational(2));
+        let (done, wait_done) = std::sync::mpsc::channel();
+        let mutator = crate::thread::spawn(move || {
+            let lock = Arc::new(parking_lot::Mutex::new(()));
+            let registry = {
+                let lock = lock.clone();
+                FinalizationRegistry::new(move |_: ()| drop(lock.lock()))
+            };
+            let guard = lock.lock();
+            registry.register(Handle::from(mt_alloc(0usize, false)), ());
+            // cleanup needs lock held by allocating thread, so minor collections started by allocation must
+            // not wait for it.
+            for i in 0..100_000usize {
+                mt_alloc(i, false);
+            }
+            drop(guard);
+            done.send(()).unwrap();
+        });
+        crate::enter_native(|| {
+            wait_done
+                .recv_timeout(std::time::Duration::from_secs(10))
+                .expect("allocation waits for cleanups");
+            mutator.join().unwrap();
+        });
+        assert!(heap.stats().minor_cycles > 0);
+        finish(&heap);
+    }
+
+    /// Counts finalized objects and finalizers that ran while the world was stopped.
+    struct Finalized {
+        heap: Arc<GlobalHeap>,
+        finalized: Arc<AtomicUsize>,
+        in_pause: Arc<AtomicBool>,
+    }
+
+    impl Traceable for Finalized {}
+    impl Finalizer for Finalized {
+        fn finalize(&mut self) {
+            if self.heap.threads.safepoint_requested() {
+                self.in_pause.store(true, Ordering::Relaxed);
+            }
+            self.finalized.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_finalizers_run_outside_pause() {
+        for finalizer_thread in [true, false].iter().copied() {
+            let heap = attached(HeapConfig {
+                finalizer_thread,
+                ..generational(1)
+            });
+            let finalized = Arc::new(AtomicUsize::new(0));
+            let in_pause = Arc::new(AtomicBool::new(false));
+            let object = || {
+                let value = Finalized {
+                    heap: heap.clone(),
+                    finalized: finalized.clone(),
+                    in_pause: in_pause.clone(),
+                };
+                mt_alloc(value, true)
+            };
+            let finalized_after = |expected| {
+                if !finalizer_thread {
+                    assert_eq!(finalized.load(Ordering::Relaxed), expected - 1);
+                    assert_eq!(heap.run_cleanups(), 1);
+                }
+                assert_eq!(finalized.load(Ordering::Relaxed), expected);
+            };
+            object();
+            heap.minor_collect();
+            finalized_after(1);
+            let old = object();
+            heap.minor_collect();
+            drop(old);
+            heap.collect_sync();
+            finalized_after(2);
+            let _live = object();
+            finish(&heap);
+            assert_eq!(finalized.load(Ordering::Relaxed), 3);
+            assert!(!in_pause.load(Ordering::Relaxed));
+        }
+    }
+}
@@ -1,11 +1,37 @@
 use crate::api::*;
+use crate::config::*;
+use crate::events::*;
+use crate::finalization::*;
 use crate::mem::*;
 use crate::space::*;
+use crate::stats::*;
+use crate::threads::*;
+use crate::weakmap::*;
+use crossbeam::deque;
 use crossbeam::queue::SegQueue;
 use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use std::sync::Arc;
+use time::Instant;
 pub const GC_WHITE: u8 = 0;
 pub const GC_GREY: u8 = 1;
 pub const GC_BLACK: u8 = 2;
+/// Object is being copied by one of GC workers.
+pub const GC_BUSY: u8 = 3;
+
+/// Object is in old space.
+pub const FLAG_OLD: u8 = 1;
+/// Object is in remembered set.
+pub const FLAG_REMEMBERED: u8 = 2;
+/// Object is in large object space, it is marked instead of copied.
+pub const FLAG_LARGE: u8 = 4;
+/// Object is pinned, it is marked in place and its page is kept at flip.
+pub const FLAG_PINNED: u8 = 8;
+/// Dead large object waiting for its finalizer, its page is kept until the finalizer is finished.
+pub const FLAG_FINALIZING: u8 = 16;
+/// Large object was finalized, its page is released by the next sweep.
+pub const FLAG_FINALIZED: u8 = 32;
+/// Object was passed to write barrier while copying is in progress, it is rescanned in the flip pause.
+pub const FLAG_WRITTEN: u8 = 64;
 
 #[cfg(not(feature = "trace-gc"))]
 const TRACE_GC: bool = false;
@@ -13,10 +39,15 @@ const TRACE_GC: bool = false;
 #[cfg(feature = "trace-gc")]
 const TRACE_GC: bool = true;
 
+/// Object header, `repr(C)` keeps header at the same offsets for all object types so it can be read from object address.
+#[repr(C)]
 pub struct HeapInner<T: super::api::Trace + ?Sized> {
     /// Foward address, initially points to `self` for read barriers.
     pub(crate) forward: AtomicUsize,
     pub(crate) color: AtomicU8,
+    pub(crate) flags: AtomicU8,
+    /// Number of minor collections survived by object.
+    pub(crate) age: u8,
     pub(crate) value: T,
 }
 impl<T: super::api::Trace + ?Sized> HeapInner<T> {
@@ -30,6 +61,15 @@ impl<T: super::api::Trace + ?Sized> HeapInner<T> {
     pub fn is_marked(&self) -> bool {
         false
     }
+    pub fn is_old(&self) -> bool {
+        self.flags.load(Ordering::Relaxed) & FLAG_OLD != 0
+    }
+    pub fn is_large(&self) -> bool {
+        self.flags.load(Ordering::Relaxed) & FLAG_LARGE != 0
+    }
+    pub fn is_pinned(&self) -> bool {
+        self.flags.load(Ordering::SeqCst) & FLAG_PINNED != 0
+    }
 }
 
 pub(crate) unsafe fn read_barrier_impl<T: Trace>(src_: *mut HeapInner<T>) -> *mut HeapInner<T> {
@@ -41,19 +81,26 @@ pub(crate) unsafe fn read_barrier_impl<T: Trace>(src_: *mut HeapInner<T>) -> *mu
     r
 }
 
-pub(crate) unsafe fn write_barrier_impl(src: *mut HeapInner<dyn Trace>) {
-    let cell = &mut *src;
-    if HEAP.state.load(Ordering::Acquire) != GC_COPYING {
-        return;
-    }
-    if cell.color.load(Ordering::Acquire) == GC_GREY {
-        // Object is in worklist,return.
-        return;
+/// Number of heaps that are copying objects, lets `write_access` skip looking up heap of current thread while no
+/// heap is copying.
+static HEAPS_COPYING: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns object mutator may write into, object that is not copied yet is copied first by `GlobalHeap::evacuate`.
+pub(crate) unsafe fn write_access<T: Trace + 'static>(src: *mut HeapInner<T>) -> *mut HeapInner<T> {
+    if HEAPS_COPYING.load(Ordering::Acquire) != 0 {
+        THREAD.with(|thread| {
+            if let Some(heap) = &*thread.borrow().heap.borrow() {
+                heap.evacuate(src);
+            }
+        });
     }
+    read_barrier_impl(src)
+}
 
-    // Push object to worklist so GC will scan object for new objects written to our object.
-    cell.color.store(GC_GREY, Ordering::Release);
-    HEAP.worklist.push(GcValue { value: src });
+/// Returns pointer to the current location of `src`, keeping its vtable.
+pub(crate) unsafe fn forwarded(src: *mut HeapInner<dyn Trace>) -> *mut HeapInner<dyn Trace> {
+    let fwd = (*src).forward.load(Ordering::Acquire);
+    src.wrapping_byte_offset(fwd.wrapping_sub(src as *mut u8 as usize) as isize)
 }
 
 pub struct GcValue {
@@ -64,23 +111,133 @@ impl GcValue {
     fn value(&self) -> &mut HeapInner<dyn Trace> {
         unsafe { &mut *self.value }
     }
-    fn relocate(&self, addr: Address) {
-        /*if self.slot.is_non_null() {
-           unsafe {
-                let slot = self.slot.to_mut_ptr::<*mut *mut u8>();
-                *slot = addr.to_mut_ptr();
-            }
-        }*/
-        if !self.value().color.load(Ordering::Acquire) != HEAP.black.load(Ordering::Relaxed) {
-            self.value().set_fwdptr(addr);
-            self.value()
-                .color
-                .store(HEAP.black.load(Ordering::Relaxed), Ordering::Release);
+}
+
+unsafe impl Send for GcValue {}
+
+/// Dead finalizable object moved out of the heap, finalizer runs after heap memory of the object was reused.
+struct DeadObject(*mut HeapInner<dyn Trace>);
+
+unsafe impl Send for DeadObject {}
+
+impl DeadObject {
+    /// Moves `object` to memory owned by returned value.
+    unsafe fn move_out(object: *mut HeapInner<dyn Trace>) -> Self {
+        let layout = std::alloc::Layout::for_value(&*object);
+        let memory = std::alloc::alloc(layout);
+        if memory.is_null() {
+            std::alloc::handle_alloc_error(layout);
         }
+        std::ptr::copy_nonoverlapping(object as *const u8, memory, layout.size());
+        let offset = (memory as usize).wrapping_sub(object as *mut u8 as usize);
+        Self(object.wrapping_byte_offset(offset as isize))
+    }
+
+    /// Runs finalizer and destructor of object and frees its memory.
+    unsafe fn finalize(self) {
+        let layout = std::alloc::Layout::for_value(&*self.0);
+        (*self.0).value.finalize();
+        std::ptr::drop_in_place(self.0);
+        std::alloc::dealloc(self.0 as *mut u8, layout);
+    }
+
+    /// Runs finalizer and destructor of large object left in its page, header stays so the sweep can release it.
+    unsafe fn finalize_in_place(self) {
+        let object = &mut *self.0;
+        object.value.finalize();
+        std::ptr::drop_in_place(&mut object.value);
+        object
+            .flags
+            .fetch_xor(FLAG_FINALIZING | FLAG_FINALIZED, Ordering::Release);
     }
 }
 
-unsafe impl Send for GcValue {}
+/// Returns cleanup that finalizes dead `object`. Finalizers are user code, so they are queued to `CleanupQueue`
+/// instead of being run while the world is stopped.
+///
+/// Memory of small objects is reused right away, so they are moved out of the heap. Large objects are finalized
+/// in place instead of being copied, their pages are kept until the finalizer is finished.
+unsafe fn finalizer(object: *mut HeapInner<dyn Trace>) -> Cleanup {
+    if (*object).is_large() {
+        (*object).flags.fetch_or(FLAG_FINALIZING, Ordering::Relaxed);
+        let object = DeadObject(object);
+        return Box::new(move || unsafe { object.finalize_in_place() });
+    }
+    let object = DeadObject::move_out(object);
+    Box::new(move || unsafe { object.finalize() })
+}
+
+/// Place where `GlobalHeap::stress` might collect, see `GcStress`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub(crate) enum StressPoint {
+    Allocation,
+    Root,
+    Safepoint,
+}
+
+/// Error returned by fallible allocation when heap reached `HeapConfig::max_heap_size` or OS can't provide memory.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct AllocError {
+    /// Size of object that could not be allocated.
+    pub size: usize,
+}
+
+impl std::fmt::Display for AllocError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "out of memory: failed to allocate {} bytes", self.size)
+    }
+}
+
+impl std::error::Error for AllocError {}
+
+/// Broken heap invariant found by `GlobalHeap::verify`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct VerifyError {
+    /// Address of reachable object that breaks the invariant.
+    pub object: Address,
+    pub reason: String,
+}
+
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "heap verification failed at {:p}: {}",
+            self.object.to_ptr::<u8>(),
+            self.reason
+        )
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// Scanned object and addresses of objects it referenced.
+type Snapshot = (*mut HeapInner<dyn Trace>, Vec<usize>);
+
+/// Store into scanned object that was not preceded by `write_barrier`, found when `HeapConfig::check_barriers`
+/// is enabled.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct MissingBarrier {
+    /// Epoch of the cycle in which store was detected.
+    pub epoch: usize,
+    /// Address of the object after cycle.
+    pub object: Address,
+    pub object_type: &'static str,
+    /// Type of the object reference to which was stored.
+    pub target_type: &'static str,
+}
+
+impl std::fmt::Display for MissingBarrier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "missing write barrier: reference to {} was stored into {} at {:p} after it was scanned",
+            self.target_type,
+            self.object_type,
+            self.object.to_ptr::<u8>()
+        )
+    }
+}
 
 pub const GC_NONE: u8 = 0;
 pub const GC_COPYING: u8 = 2;
@@ -93,10 +250,64 @@ pub struct GlobalHeap {
     fence_mutator: AtomicBool,
     needs_gc: AtomicBool,
     weak_handles: parking_lot::Mutex<Vec<*mut HeapInner<dyn Trace>>>,
+    /// Slots of `Weak` references.
+    weak_refs: parking_lot::Mutex<Vec<Arc<WeakSlot>>>,
+    /// Entries of `GcWeakMap`s.
+    pub(crate) ephemerons: parking_lot::Mutex<Vec<Arc<EphemeronTable>>>,
+    /// Objects registered in `FinalizationRegistry`s.
+    registrations: parking_lot::Mutex<Registrations>,
+    /// Roots of `Rooted`s that outlived their thread's attachment, see `GlobalHeap::detach_current_thread`.
+    detached_roots: parking_lot::Mutex<Vec<*mut dyn RootedTrait>>,
+    cleanups: Arc<CleanupQueue>,
+    /// Cleanups and finalizers of objects collected while the world is stopped, queued once it is resumed.
+    deferred_cleanups: parking_lot::Mutex<Vec<Cleanup>>,
+    /// Number of `Pinned` guards of pinned objects, objects unpinned while copying is in progress stay with no
+    /// guards until the flip.
+    pinned: parking_lot::Mutex<fxhash::FxHashMap<usize, usize>>,
+    /// References of objects scanned in current cycle by their addresses, kept if `HeapConfig::check_barriers`
+    /// is enabled. Rescanned objects replace their snapshots.
+    snapshots: parking_lot::Mutex<fxhash::FxHashMap<usize, Snapshot>>,
+    /// Objects written by mutators while copying is in progress. GC worker might scan such object between write
+    /// barrier and the store that follows it, so they are rescanned in the flip pause.
+    written: SegQueue<GcValue>,
+    missing_barriers: parking_lot::Mutex<Vec<MissingBarrier>>,
+    /// Allocations counted by `GcStress::EveryNthAllocation`.
+    stress_allocations: AtomicUsize,
+    finalizer: parking_lot::Mutex<Option<std::thread::JoinHandle<()>>>,
     from_space: parking_lot::Mutex<Space>,
     to_space: parking_lot::Mutex<Space>,
+    large_space: parking_lot::Mutex<LargeObjectSpace>,
+    /// Young generation, empty if generational collection is disabled.
+    nursery: parking_lot::Mutex<Space>,
+    /// Objects that survived minor collection but are not old enough to be promoted.
+    survivor: parking_lot::Mutex<Space>,
+    /// Old objects that might point to young objects.
+    remembered: SegQueue<GcValue>,
+    /// Finalizable objects in young generation.
+    young_handles: parking_lot::Mutex<Vec<*mut HeapInner<dyn Trace>>>,
+    needs_minor_gc: AtomicBool,
     white: AtomicU8,
     black: AtomicU8,
+    collector_lock: parking_lot::Mutex<()>,
+    collector_cond: parking_lot::Condvar,
+    pub(crate) collector: parking_lot::Mutex<Option<std::thread::JoinHandle<()>>>,
+    /// Threads that copy objects together with collector thread, `HeapConfig::collector_threads - 1` of them.
+    helper_threads: parking_lot::Mutex<Vec<std::thread::JoinHandle<()>>>,
+    helpers: parking_lot::Mutex<CopyHelpers>,
+    helpers_cond: parking_lot::Condvar,
+    /// Number of finished collection cycles.
+    epoch: AtomicUsize,
+    cycle_lock: parking_lot::Mutex<()>,
+    cycle_cond: parking_lot::Condvar,
+    /// Bytes allocated by mutators since heap was created.
+    allocated_bytes: AtomicUsize,
+    /// Bytes committed by all spaces.
+    committed: Arc<AtomicUsize>,
+    /// Statistics of cycle in progress.
+    cycle: parking_lot::Mutex<CycleStats>,
+    stats: parking_lot::Mutex<HeapStats>,
+    callbacks: parking_lot::Mutex<Callbacks>,
+    config: HeapConfig,
     pub(crate) threads: crate::threads::Threads,
 }
 
@@ -104,190 +315,2431 @@ unsafe impl Send for GlobalHeap {}
 unsafe impl Sync for GlobalHeap {}
 
 impl GlobalHeap {
+    /// Scans roots and starts new collection cycle. Copying is done by the collector thread.
+    ///
+    /// Does nothing if collection cycle is already in progress.
     pub fn collect(&self) {
-        //self.state.store(GC_INIT, Ordering::Release);
-
-        crate::safepoint::stop_the_world(|mutators| {
+        self.verify_if_enabled();
+        let (started, pause) = self.stop_the_world(|mutators| {
+            if self.state.load(Ordering::Acquire) != GC_NONE {
+                return false;
+            }
             log::trace!("Start GC");
+            self.retire_tlabs(mutators);
+            let mut cycle = CycleStats::default();
+            self.notify(GcEvent::RootScan, &cycle);
+            if self.config.generational() {
+                // concurrent copying works only with old space, so young objects are promoted first.
+                self.minor_collect_impl(mutators, true, &mut cycle);
+            }
+            *self.cycle.lock() = cycle;
 
-            for thread in mutators.iter() {
-                thread.rootset.borrow_mut().retain(|root| unsafe {
-                    if (&**root).is_rooted() {
-                        let value = GcValue {
-                            value: (&**root).inner(),
-                        };
-                        HEAP.worklist.push(value);
-                        true
-                    } else {
-                        let _ = Box::from_raw(*root);
-                        false
-                    }
-                });
+            self.for_each_root(mutators, |root| {
+                self.worklist.push(GcValue {
+                    value: root.inner(),
+                })
+            });
+            self.needs_gc.store(false, Ordering::Relaxed);
+            if self.config.protect_evacuated {
+                // to-space is reused by this cycle.
+                self.to_space.lock().unprotect();
             }
-            HEAP.state.store(GC_COPYING, Ordering::Relaxed);
+            self.state.store(GC_COPYING, Ordering::Release);
+            HEAPS_COPYING.fetch_add(1, Ordering::AcqRel);
+            true
         });
+        if !started {
+            return;
+        }
+        self.cycle.lock().add_pause(pause);
         log::trace!("Resume threads");
+        let _lock = self.collector_lock.lock();
+        self.collector_cond.notify_one();
     }
-    pub fn new() -> Self {
-        Self {
-            white: AtomicU8::new(GC_WHITE),
-            black: AtomicU8::new(GC_BLACK),
-            needs_gc: AtomicBool::new(false),
-            to_space: parking_lot::Mutex::new(Space::new(32 * 1024)),
-            from_space: parking_lot::Mutex::new(Space::new(32 * 1024)),
-            worklist: SegQueue::new(),
-            state: AtomicU8::new(0),
-            fence_mutator: AtomicBool::new(false),
-            weak_handles: parking_lot::Mutex::new(vec![]),
-            threads: crate::threads::Threads::new(),
+
+    /// Runs full collection cycle and waits until it's finished, i.e. all unreachable objects
+    /// are finalized and their memory can be reused.
+    ///
+    /// If another cycle is in progress it is finished first since it might have scanned roots before this call.
+    /// Finalizers are waited for only if `HeapConfig::finalizer_thread` is enabled, otherwise they are run by
+    /// `run_cleanups`.
+    pub fn collect_sync(&self) {
+        self.collect_and_wait();
+        self.wait_for_cleanups();
+    }
+
+    /// Runs full collection cycle and waits until it's finished, cleanups of collected objects are only queued.
+    /// Used by collections started from allocation, which must not wait for user code.
+    fn collect_and_wait(&self) {
+        let epoch = self.epoch();
+        if self.state.load(Ordering::Acquire) != GC_NONE {
+            self.wait_for_cycle(epoch);
         }
+        let epoch = self.epoch();
+        self.collect();
+        self.wait_for_cycle(epoch);
     }
-    fn flip() {
-        let mut x = HEAP.to_space.lock();
 
-        let mut y = HEAP.from_space.lock();
-        y.reset_pages();
-        std::mem::swap(&mut *x, &mut *y);
+    /// Waits until finalizer thread runs cleanups queued so far, current thread is parked while waiting.
+    fn wait_for_cleanups(&self) {
+        if !self.config.finalizer_thread {
+            return;
+        }
+        let thread = THREAD.with(|thread| thread.borrow().clone());
+        thread.park();
+        self.cleanups.wait();
+        thread.unpark();
     }
-    fn visit(value: &mut HeapInner<dyn Trace>) {
-        value.value.references().iter().for_each(|item| unsafe {
-            HEAP.worklist.push(GcValue {
-                value: (&**item).inner(),
-            })
+
+    /// Returns number of finished collection cycles.
+    pub fn epoch(&self) -> usize {
+        self.epoch.load(Ordering::Acquire)
+    }
+
+    /// Waits until cycle that follows `epoch` is finished (or until heap is shut down).
+    ///
+    /// Current thread is parked while waiting so it doesn't block collector.
+    pub fn wait_for_cycle(&self, epoch: usize) {
+        let thread = THREAD.with(|thread| thread.borrow().clone());
+        thread.park();
+        {
+            let mut lock = self.cycle_lock.lock();
+            while self.epoch() <= epoch && self.state.load(Ordering::Acquire) != GC_TERMINATE {
+                self.cycle_cond.wait(&mut lock);
+            }
+        }
+        thread.unpark();
+    }
+
+    /// Stops the world and collects young generation: objects reachable from roots and remembered set are
+    /// copied to survivor space or promoted to old space.
+    ///
+    /// Does nothing if generational collection is disabled or full collection cycle is in progress. Like
+    /// `collect_sync`, waits for finalizers of collected objects if `HeapConfig::finalizer_thread` is enabled.
+    pub fn minor_collect(&self) {
+        self.minor_collect_with(false);
+        self.wait_for_cleanups();
+    }
+
+    /// Runs minor collection, `promote_all` promotes all surviving young objects.
+    fn minor_collect_with(&self, promote_all: bool) {
+        if !self.config.generational() {
+            return;
+        }
+        self.verify_if_enabled();
+        let mut cycle = CycleStats {
+            minor: true,
+            ..Default::default()
+        };
+        let (started, pause) = self.stop_the_world(|mutators| {
+            if self.state.load(Ordering::Acquire) != GC_NONE {
+                return false;
+            }
+            self.retire_tlabs(mutators);
+            self.notify(GcEvent::RootScan, &cycle);
+            self.minor_collect_impl(mutators, promote_all, &mut cycle);
+            self.notify(GcEvent::Flip, &cycle);
+            true
         });
+        if started {
+            cycle.epoch = self.epoch();
+            cycle.add_pause(pause);
+            self.finish_cycle(cycle);
+            self.verify_if_enabled();
+        }
     }
-    fn flip_colours() {
-        let white = HEAP.white.load(Ordering::Relaxed);
-        let black = HEAP.black.load(Ordering::Relaxed);
-        HEAP.white.store(black, Ordering::Relaxed);
-        HEAP.black.store(white, Ordering::Relaxed);
+
+    /// Stops the world and checks that all objects reachable from roots are in from-space, nursery or large object
+    /// space, are not forwarded and have current white colour. Returns number of checked objects.
+    ///
+    /// Cycle in progress is finished first.
+    pub fn verify(&self) -> Result<usize, VerifyError> {
+        assert_ne!(
+            self.state.load(Ordering::Relaxed),
+            GC_TERMINATE,
+            "verification of heap that was shut down"
+        );
+        loop {
+            let epoch = self.epoch();
+            match self.try_verify() {
+                Some(result) => return result,
+                None => self.wait_for_cycle(epoch),
+            }
+        }
     }
-    fn collect_impl() {
-        // copy objects
-        Self::process_grey();
-        // disable write barriers
-        HEAP.state.store(GC_NONE, Ordering::Release);
-        // sweep objects that needs sweeping.
-        let mut handles = HEAP.weak_handles.lock();
-        for i in (0..handles.len()).rev() {
-            if unsafe {
-                (&*handles[i]).color.load(Ordering::Relaxed) == HEAP.white.load(Ordering::Relaxed)
-            } {
-                let item = handles.swap_remove(i);
-                unsafe {
-                    (&mut *item).value.finalize();
-                    std::ptr::drop_in_place(item);
+
+    /// Runs verification if `HeapConfig::verify` is enabled, panics if heap is broken. Skipped if other cycle
+    /// is in progress.
+    fn verify_if_enabled(&self) {
+        if !self.config.verify {
+            return;
+        }
+        if let Some(Err(error)) = self.try_verify() {
+            panic!("{}", error);
+        }
+    }
+
+    /// Verifies heap unless collection cycle is in progress.
+    fn try_verify(&self) -> Option<Result<usize, VerifyError>> {
+        self.stop_the_world(|mutators| {
+            if self.state.load(Ordering::Acquire) != GC_NONE {
+                return None;
+            }
+            Some(self.verify_impl(mutators))
+        })
+        .0
+    }
+
+    fn verify_impl(&self, mutators: &[Arc<MutatorThread>]) -> Result<usize, VerifyError> {
+        let white = self.white.load(Ordering::Relaxed);
+        let from_space = self.from_space.lock();
+        let to_space = self.to_space.lock();
+        let nursery = self.nursery.lock();
+        let survivor = self.survivor.lock();
+        let large_space = self.large_space.lock();
+        let mut stack = vec![];
+        self.for_each_root(mutators, |root| stack.push(root.inner()));
+        let mut visited = fxhash::FxHashSet::default();
+        while let Some(object) = stack.pop() {
+            let address = Address::from_ptr(object as *const u8);
+            if !visited.insert(address.to_usize()) {
+                continue;
+            }
+            let error = |reason: String| {
+                Err(VerifyError {
+                    object: address,
+                    reason,
+                })
+            };
+            // memory of reset pages is reused, so their objects are not even read.
+            if to_space.contains(address) || survivor.contains(address) {
+                return error("object is in reset page".to_owned());
+            }
+            let young = nursery.contains(address);
+            let large = large_space.contains(address);
+            if !young && !large && !from_space.contains(address) {
+                return error("object is outside of heap".to_owned());
+            }
+            let object = unsafe { &*object };
+            if object.fwdptr() != address {
+                return error(format!(
+                    "object is forwarded to {:p}",
+                    object.fwdptr().to_ptr::<u8>()
+                ));
+            }
+            let color = object.color.load(Ordering::Relaxed);
+            if color != white {
+                return error(format!("object has colour {}, white is {}", color, white));
+            }
+            if object.is_old() == young || object.is_large() != large {
+                return error(format!(
+                    "object flags {:#x} don't match its space",
+                    object.flags.load(Ordering::Relaxed)
+                ));
+            }
+            object.value.trace(&mut Tracer::new(&mut |reference| {
+                stack.push(unsafe { (*reference).inner() })
+            }));
+        }
+        Ok(visited.len())
+    }
+
+    /// Stops the world and runs `f`, returns its result and the pause.
+    ///
+    /// Cleanups of objects collected by `f` are queued after the world is resumed, so finalizer thread doesn't
+    /// run user code during the pause.
+    fn stop_the_world<R>(&self, f: impl FnOnce(&[Arc<MutatorThread>]) -> R) -> (R, Pause) {
+        let start = Instant::now();
+        let (ret, time_to_safepoint) =
+            crate::safepoint::stop_the_world(&self.threads, &self.config, f);
+        let pause = Pause {
+            duration: start.elapsed(),
+            time_to_safepoint,
+        };
+        self.cleanups
+            .push(std::mem::take(&mut *self.deferred_cleanups.lock()));
+        (ret, pause)
+    }
+
+    /// Adds finished cycle to heap statistics and runs `CycleEnd` callbacks.
+    fn finish_cycle(&self, mut cycle: CycleStats) {
+        let mut stats = self.stats.lock();
+        let allocated = self.allocated_bytes.load(Ordering::Relaxed);
+        cycle.bytes_allocated = allocated - stats.bytes_allocated;
+        // stored value is amount of bytes allocated before last cycle, `stats` returns current one.
+        stats.bytes_allocated = allocated;
+        stats.record(cycle.clone(), self.config.stats_history);
+        drop(stats);
+        self.notify(GcEvent::CycleEnd, &cycle);
+    }
+
+    /// Registers callback that is called with statistics of current cycle each time `event` happens,
+    /// both for full and minor collections.
+    ///
+    /// `RootScan` and `Flip` callbacks are called while the world is stopped, so they can update raw pointers
+    /// to heap objects but must not allocate in this heap or wait for mutators.
+    pub fn add_callback(
+        &self,
+        event: GcEvent,
+        callback: impl Fn(&CycleStats) + Send + Sync + 'static,
+    ) -> CallbackId {
+        self.callbacks.lock().add(event, Arc::new(callback))
+    }
+
+    /// Removes callback, returns false if it was already removed.
+    pub fn remove_callback(&self, id: CallbackId) -> bool {
+        self.callbacks.lock().remove(id)
+    }
+
+    fn notify(&self, event: GcEvent, cycle: &CycleStats) {
+        let callbacks = self.callbacks.lock().get(event);
+        for callback in callbacks {
+            callback(cycle);
+        }
+    }
+
+    /// Returns statistics of finished cycles and current memory usage.
+    pub fn stats(&self) -> HeapStats {
+        let mut stats = self.stats.lock().clone();
+        stats.bytes_allocated = self.allocated_bytes.load(Ordering::Relaxed);
+        stats.committed_bytes = self.committed.load(Ordering::Relaxed);
+        stats.from_space = SpaceStats::of(&self.from_space.lock());
+        stats.to_space = SpaceStats::of(&self.to_space.lock());
+        stats.nursery = SpaceStats::of(&self.nursery.lock());
+        stats.survivor = SpaceStats::of(&self.survivor.lock());
+        stats.large_space = SpaceStats::of_large(&self.large_space.lock());
+        stats
+    }
+
+    fn minor_collect_impl(
+        &self,
+        mutators: &[Arc<MutatorThread>],
+        promote_all: bool,
+        cycle: &mut CycleStats,
+    ) {
+        log::trace!("Start minor GC");
+        self.needs_minor_gc.store(false, Ordering::Relaxed);
+        let mut nursery = self.nursery.lock();
+        let mut survivor = self.survivor.lock();
+        if self.config.protect_evacuated {
+            survivor.unprotect();
+        }
+        let mut old_space = self.from_space.lock();
+        let mut scan = vec![];
+        let mut evacuate = |object: *mut HeapInner<dyn Trace>,
+                            scan: &mut Vec<*mut HeapInner<dyn Trace>>| unsafe {
+            let copy = forwarded(object);
+            if copy as *mut u8 != object as *mut u8 {
+                return copy;
+            }
+            let size = std::mem::size_of_val(&*object);
+            let age = (*object).age.saturating_add(1);
+            let mut promote = promote_all || age >= self.config.promotion_age;
+            if !promote && !survivor.may_allocate_in_current(size) {
+                promote = true;
+            }
+            let memory = if promote {
+                let mut gc = false;
+                let memory = old_space.allocate(size, &mut gc);
+                if gc {
+                    self.needs_gc.store(true, Ordering::Relaxed);
                 }
+                memory
+            } else {
+                survivor.allocate(size, &mut false)
+            };
+            std::ptr::copy_nonoverlapping(object as *const u8, memory.to_mut_ptr::<u8>(), size);
+            log::trace!("Minor GC: Copy {:p}->{:p}", object, memory.to_ptr::<u8>());
+            (*object).set_fwdptr(memory);
+            let copy = forwarded(object);
+            (*copy).set_fwdptr(memory);
+            (*copy).age = age;
+            if promote {
+                (*copy).flags.store(FLAG_OLD, Ordering::Relaxed);
+            }
+            cycle.bytes_copied += size;
+            cycle.surviving_objects += 1;
+            scan.push(copy);
+            copy
+        };
+
+        self.for_each_root(mutators, |root| unsafe {
+            let object = root.inner();
+            if !(*object).is_old() {
+                let copy = evacuate(object, &mut scan);
+                root.relocate(
+                    Address::from_ptr(object as *const u8),
+                    Address::from_ptr(copy as *const u8),
+                );
+            }
+        });
+        while let Ok(object) = self.remembered.pop() {
+            unsafe {
+                (*object.value)
+                    .flags
+                    .fetch_and(!FLAG_REMEMBERED, Ordering::Relaxed);
+            }
+            scan.push(object.value);
+        }
+        let relocated = |object: &HeapInner<u8>| {
+            if object.is_old() {
+                Some(Address::from_ptr(object as *const _ as *const u8))
+            } else if object.fwdptr() != Address::from_ptr(object as *const _ as *const u8) {
+                Some(object.fwdptr())
             } else {
+                None
+            }
+        };
+        loop {
+            while let Some(object) = scan.pop() {
                 unsafe {
-                    (&*handles[i])
-                        .color
-                        .store(HEAP.white.load(Ordering::Relaxed), Ordering::Relaxed);
+                    let mut has_young = false;
+                    (*object).value.trace(&mut Tracer::new(&mut |slot| {
+                        let target = (*slot).inner();
+                        if (*target).is_old() {
+                            return;
+                        }
+                        let copy = evacuate(target, &mut scan);
+                        (*slot).relocate(
+                            Address::from_ptr(target as *const u8),
+                            Address::from_ptr(copy as *const u8),
+                        );
+                        has_young |= !(*copy).is_old();
+                    }));
+                    if has_young && (*object).is_old() {
+                        self.remember(object);
+                    }
                 }
             }
+            // values of ephemerons with surviving keys survive too and might make more keys reachable.
+            self.for_each_ephemeron(|key, value| unsafe {
+                if relocated(key).is_some() && !(*value).is_old() {
+                    evacuate(value, &mut scan);
+                }
+            });
+            if scan.is_empty() {
+                break;
+            }
         }
-        crate::safepoint::stop_the_world(|_| {
-            log::trace!("GC Worker: flip");
-            Self::flip();
-            Self::flip_colours();
+
+        self.update_weak_refs(relocated);
+        self.update_ephemerons(relocated);
+        self.deferred_cleanups
+            .lock()
+            .append(&mut self.registrations.lock().update(relocated));
+        let mut young_handles = self.young_handles.lock();
+        let mut weak_handles = self.weak_handles.lock();
+        let mut dead = vec![];
+        young_handles.retain_mut(|item| unsafe {
+            let copy = forwarded(*item);
+            if copy as *mut u8 == *item as *mut u8 {
+                dead.push(finalizer(*item));
+                return false;
+            }
+            if (*copy).is_old() {
+                weak_handles.push(copy);
+                return false;
+            }
+            *item = copy;
+            true
         });
-        /*HEAP.weak_handles.lock().retain(|item| {
-            if unsafe { (&**item).color.load(Ordering::Relaxed) == GC_WHITE } {
-                unsafe {
-                    (&mut **item).value.finalize();
-                    std::ptr::drop_in_place(*item);
-                }
-                false
-            } else {
-                true
+        self.deferred_cleanups.lock().append(&mut dead);
+        nursery.reset_pages();
+        if self.poison_evacuated() {
+            nursery.poison();
+        }
+        std::mem::swap(&mut *nursery, &mut *survivor);
+        if self.config.protect_evacuated {
+            survivor.protect(self.epoch(), true);
+        }
+    }
+
+    /// Adds old object to remembered set unless it's already there.
+    fn remember(&self, object: *mut HeapInner<dyn Trace>) {
+        let flags = unsafe { &(*object).flags };
+        if flags.fetch_or(FLAG_REMEMBERED, Ordering::AcqRel) & FLAG_REMEMBERED == 0 {
+            self.remembered.push(GcValue { value: object });
+        }
+    }
+
+    /// Starts collection cycle if allocations went over the heap threshold.
+    pub(crate) fn collect_if_needed(&self) {
+        if self.needs_minor_gc.load(Ordering::Relaxed) {
+            self.minor_collect_with(false);
+        }
+        if self.needs_gc.load(Ordering::Relaxed) && self.state.load(Ordering::Acquire) == GC_NONE {
+            self.collect();
+        }
+    }
+
+    /// Runs full synchronous collection if `HeapConfig::stress` asks for it at `point`.
+    pub(crate) fn stress(&self, point: StressPoint) {
+        let collect = match (self.config.stress, point) {
+            (GcStress::EveryAllocation, StressPoint::Allocation) => true,
+            (GcStress::EveryAllocationAndRoot, StressPoint::Allocation)
+            | (GcStress::EveryAllocationAndRoot, StressPoint::Root) => true,
+            (GcStress::EveryNthAllocation(n), StressPoint::Allocation) => {
+                let allocations = self.stress_allocations.fetch_add(1, Ordering::Relaxed) + 1;
+                allocations.is_multiple_of(n.max(1))
             }
-        })*/
+            (GcStress::EveryRoot, StressPoint::Root) => true,
+            (GcStress::EverySafepoint, StressPoint::Safepoint) => true,
+            _ => false,
+        };
+        if collect {
+            self.collect_and_wait();
+        }
     }
 
-    pub fn allocate<T: Trace + 'static>(&self, value: T, finalize: bool) -> *mut HeapInner<T> {
-        let mut space = self.from_space.lock();
-        let mut gc = false;
-        let memory = space.allocate(std::mem::size_of::<HeapInner<T>>(), &mut gc);
-        log::trace!("Allocate {:p}", memory.to_ptr::<u8>());
-        if self.state.load(Ordering::Relaxed) != GC_COPYING {
-            self.needs_gc.store(gc, Ordering::Relaxed);
+    /// Evacuated memory is filled with `POISON` in stress mode and when it is protected.
+    fn poison_evacuated(&self) -> bool {
+        self.config.stress != GcStress::Off || self.config.protect_evacuated
+    }
+
+    /// Makes sure object rooted while copying is in progress gets copied.
+    pub(crate) fn shade(&self, object: *mut HeapInner<dyn Trace>) {
+        if self.state.load(Ordering::Acquire) == GC_COPYING {
+            self.worklist.push(GcValue { value: object });
         }
-        unsafe {
-            let raw = memory.to_mut_ptr::<HeapInner<T>>();
-            raw.write(HeapInner {
-                forward: AtomicUsize::new(raw as usize),
-                color: AtomicU8::new(self.white.load(Ordering::Relaxed)),
-                value,
-            });
-            if finalize {
-                self.weak_handles.lock().push(raw);
+    }
+
+    /// Creates new heap and spawns its collector thread.
+    pub fn new(config: HeapConfig) -> Arc<Self> {
+        let page_size = align_usize(config.page_size, page_size());
+        let mut from_space = Space::new(page_size);
+        from_space.size_limit = config.size_limit(0);
+        let mut large_space = LargeObjectSpace::empty();
+        large_space.size_limit = config.size_limit(0);
+        let young_space = || {
+            if config.generational() {
+                Space::new(align_usize(config.nursery_size, self::page_size()))
+            } else {
+                Space::empty()
             }
+        };
+        // all spaces share one counter of committed memory checked against `HeapConfig::max_heap_size`.
+        let committed = Arc::new(AtomicUsize::new(0));
+        let share = |mut space: Space| {
+            committed.fetch_add(space.size, Ordering::Relaxed);
+            space.committed = committed.clone();
+            space
+        };
+        large_space.committed = committed.clone();
+        let heap = Arc::new(Self {
+            white: AtomicU8::new(GC_WHITE),
+            black: AtomicU8::new(GC_BLACK),
+            needs_gc: AtomicBool::new(false),
+            to_space: parking_lot::Mutex::new(share(Space::new(page_size))),
+            from_space: parking_lot::Mutex::new(share(from_space)),
+            large_space: parking_lot::Mutex::new(large_space),
+            nursery: parking_lot::Mutex::new(share(young_space())),
+            survivor: parking_lot::Mutex::new(share(young_space())),
+            committed,
+            remembered: SegQueue::new(),
+            young_handles: parking_lot::Mutex::new(vec![]),
+            needs_minor_gc: AtomicBool::new(false),
+            worklist: SegQueue::new(),
+            state: AtomicU8::new(0),
+            fence_mutator: AtomicBool::new(false),
+            weak_handles: parking_lot::Mutex::new(vec![]),
+            weak_refs: parking_lot::Mutex::new(vec![]),
+            ephemerons: parking_lot::Mutex::new(vec![]),
+            registrations: parking_lot::Mutex::new(Registrations::default()),
+            detached_roots: parking_lot::Mutex::new(vec![]),
+            cleanups: Arc::new(CleanupQueue::default()),
+            deferred_cleanups: parking_lot::Mutex::new(vec![]),
+            pinned: parking_lot::Mutex::new(Default::default()),
+            snapshots: parking_lot::Mutex::new(Default::default()),
+            written: SegQueue::new(),
+            missing_barriers: parking_lot::Mutex::new(vec![]),
+            stress_allocations: AtomicUsize::new(0),
+            finalizer: parking_lot::Mutex::new(None),
+            collector_lock: parking_lot::Mutex::new(()),
+            collector_cond: parking_lot::Condvar::new(),
+            collector: parking_lot::Mutex::new(None),
+            helper_threads: parking_lot::Mutex::new(vec![]),
+            helpers: parking_lot::Mutex::new(CopyHelpers::default()),
+            helpers_cond: parking_lot::Condvar::new(),
+            epoch: AtomicUsize::new(0),
+            cycle_lock: parking_lot::Mutex::new(()),
+            cycle_cond: parking_lot::Condvar::new(),
+            allocated_bytes: AtomicUsize::new(0),
+            cycle: parking_lot::Mutex::new(CycleStats::default()),
+            stats: parking_lot::Mutex::new(HeapStats::default()),
+            callbacks: parking_lot::Mutex::new(Callbacks::default()),
+            config,
+            threads: crate::threads::Threads::new(),
+        });
+        let collector = heap.clone();
+        *heap.collector.lock() = Some(std::thread::spawn(move || collect_routine(collector)));
+        *heap.helper_threads.lock() = (1..heap.config.collector_threads)
+            .map(|_| {
+                let heap = heap.clone();
+                std::thread::spawn(move || copy_helper_routine(heap))
+            })
+            .collect();
+        if heap.config.stress == GcStress::EverySafepoint {
+            // `safepoint!` polls reach `gc_guard` only while safepoint is requested.
+            crate::safepoint::SAFEPOINT_POLL.fetch_add(1, Ordering::SeqCst);
+        }
+        if heap.config.finalizer_thread {
+            let cleanups = heap.cleanups.clone();
+            *heap.finalizer.lock() = Some(std::thread::spawn(move || cleanups.run()));
+        }
+        heap
+    }
 
-            raw
+    /// Stops collector and finalizer threads, runs finalizers of all objects that need finalization and cleanups of
+    /// all registrations in `FinalizationRegistry`s, then unmaps heap memory.
+    ///
+    /// Cycle in progress is finished first. Heap must not be used for allocation after shutdown.
+    pub fn shutdown(&self) {
+        loop {
+            let epoch = self.epoch();
+            match self.state.compare_exchange(
+                GC_NONE,
+                GC_TERMINATE,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(GC_TERMINATE) => return,
+                Err(_) => self.wait_for_cycle(epoch),
+            }
+        }
+        {
+            let _lock = self.collector_lock.lock();
+            self.collector_cond.notify_one();
+        }
+        {
+            let _lock = self.cycle_lock.lock();
+            self.cycle_cond.notify_all();
+        }
+        if self.config.stress == GcStress::EverySafepoint {
+            crate::safepoint::SAFEPOINT_POLL.fetch_sub(1, Ordering::SeqCst);
+        }
+        if let Some(collector) = self.collector.lock().take() {
+            collector.join().expect("collector thread panicked");
+        }
+        self.helpers.lock().terminate = true;
+        self.helpers_cond.notify_all();
+        for helper in self.helper_threads.lock().drain(..) {
+            helper.join().expect("GC helper thread panicked");
+        }
+        let mut young_handles = self.young_handles.lock();
+        self.cleanups.push(
+            self.weak_handles
+                .lock()
+                .drain(..)
+                .chain(young_handles.drain(..))
+                .map(|item| unsafe { finalizer(item) })
+                .collect(),
+        );
+        for slot in self.weak_refs.lock().drain(..) {
+            slot.target.store(0, Ordering::Release);
+        }
+        for table in self.ephemerons.lock().drain(..) {
+            table.entries.lock().clear();
         }
+        self.pinned.lock().clear();
+        // live `Rooted`s still point to their entries, those are leaked.
+        self.detached_roots.lock().retain(|root| unsafe {
+            if (**root).is_rooted() {
+                return true;
+            }
+            let _ = Box::from_raw(*root);
+            false
+        });
+        self.cleanups.push(self.registrations.lock().drain());
+        self.cleanups.stop();
+        if let Some(finalizer) = self.finalizer.lock().take() {
+            finalizer.join().expect("finalizer thread panicked");
+        }
+        self.cleanups.run_pending();
+        self.from_space.lock().clear();
+        self.to_space.lock().clear();
+        self.large_space.lock().clear();
+        self.nursery.lock().clear();
+        self.survivor.lock().clear();
+        log::trace!("Heap shut down");
     }
 
-    fn process_grey() {
-        while HEAP.worklist.is_empty() == false {
-            let value: GcValue = loop {
-                match HEAP.worklist.pop() {
-                    Ok(val) => break val,
-                    Err(x) => panic!("{}", x),
-                }
-            };
+    pub fn config(&self) -> &HeapConfig {
+        &self.config
+    }
+
+    /// Attaches current thread to this heap, after that `mt_alloc` and `mt_root` called from this thread use this heap.
+    ///
+    /// Thread can be attached only to one heap at a time.
+    pub fn attach_current_thread(self: &Arc<Self>) {
+        THREAD.with(|thread| {
+            let thread = thread.borrow();
+            let mut heap = thread.heap.borrow_mut();
+            assert!(heap.is_none(), "thread is already attached to GC heap");
+            *heap = Some(self.clone());
+        });
+        self.threads.attach_current_thread();
+    }
 
-            if value.value().color.load(Ordering::Relaxed) == GC_WHITE {
-                let hvalue = HEAP
-                    .to_space
-                    .lock()
-                    .allocate(std::mem::size_of_val(value.value()), &mut false);
-                value
-                    .value()
-                    .forward
-                    .store(hvalue.to_usize(), Ordering::Release);
+    pub fn detach_current_thread(&self) {
+        THREAD.with(|thread| {
+            let thread = thread.borrow();
+            self.retire_tlab(
+                &mut thread.tlab.borrow_mut(),
+                &mut self.allocation_space().lock(),
+            );
+            // entries of dropped `Rooted`s are released, live ones are moved to heap so objects they point to stay
+            // alive until they are dropped. Thread is still attached, so collector can't scan roots meanwhile.
+            let mut detached_roots = self.detached_roots.lock();
+            for root in thread.rootset.borrow_mut().drain(..) {
                 unsafe {
-                    std::ptr::copy_nonoverlapping(
-                        value.value() as *mut _ as *const u8,
-                        hvalue.to_mut_ptr::<u8>(),
-                        std::mem::size_of_val(value.value()),
-                    );
+                    if (*root).is_rooted() {
+                        detached_roots.push(root);
+                    } else {
+                        let _ = Box::from_raw(root);
+                    }
                 }
-                log::trace!(
-                    "GC Worker: Copy {:p}->{:p}",
-                    value.value(),
-                    hvalue.to_mut_ptr::<u8>()
-                );
-                Self::visit(value.value());
-                value.relocate(hvalue);
-            } else {
-                //value.relocate(value.value().fwdptr());
             }
+        });
+        self.threads.detach_current_thread();
+        THREAD.with(|thread| {
+            let thread = thread.borrow();
+            *thread.heap.borrow_mut() = None;
+            thread.unpark();
+        });
+    }
+
+    pub(crate) unsafe fn write_barrier(&self, src: *mut HeapInner<dyn Trace>) {
+        let cell = &mut *src;
+        if self.state.load(Ordering::Acquire) != GC_COPYING {
+            // old object might point to young objects now.
+            if self.config.generational() && cell.is_old() {
+                self.remember(src);
+            }
+            return;
+        }
+        self.evacuate(src);
+        let object = forwarded(src);
+        if (*object).flags.fetch_or(FLAG_WRITTEN, Ordering::AcqRel) & FLAG_WRITTEN == 0 {
+            self.written.push(GcValue { value: object });
         }
+        // White objects are not copied yet and will be scanned once they are, grey objects
+        // are already in the worklist. Only black objects have to be rescanned.
+        let black = self.black.load(Ordering::Relaxed);
+        if cell
+            .color
+            .compare_exchange(black, GC_GREY, Ordering::AcqRel, Ordering::Relaxed)
+            .is_err()
+        {
+            return;
+        }
+
+        // Push object to worklist so GC will scan object for new objects written to our object.
+        self.worklist.push(GcValue { value: src });
     }
-}
 
-fn collect_routine() {
-    loop {
-        let mut attempt = 0;
-        while HEAP.state.load(Ordering::Relaxed) == GC_NONE {
-            if attempt < 512 {
+    /// Copies object that is not copied yet before mutator writes into it. GC worker copying the object would
+    /// lose stores done after its copy was made, so mutator waits if a worker is already copying the object.
+    /// Copy is scanned by worker that takes the object from worklist.
+    pub(crate) fn evacuate(&self, object: *mut HeapInner<dyn Trace>) {
+        if self.state.load(Ordering::Acquire) != GC_COPYING {
+            return;
+        }
+        let value = unsafe { &*object };
+        let white = self.white.load(Ordering::Relaxed);
+        loop {
+            let color = value.color.load(Ordering::Acquire);
+            if color == GC_BUSY {
                 std::thread::yield_now();
+            } else if color != white || value.is_large() {
+                // large objects are marked in place.
+                return;
+            } else if value
+                .color
+                .compare_exchange(white, GC_BUSY, Ordering::SeqCst, Ordering::Relaxed)
+                .is_ok()
+            {
+                break;
+            }
+        }
+        if !value.is_pinned() {
+            let size = std::mem::size_of_val(value);
+            let memory = self.to_space.lock().allocate(size, &mut false);
+            unsafe {
+                std::ptr::copy_nonoverlapping(object as *const u8, memory.to_mut_ptr::<u8>(), size);
+                value.set_fwdptr(memory);
+                let copy = &*forwarded(object);
+                copy.set_fwdptr(memory);
+                copy.color
+                    .store(self.black.load(Ordering::Relaxed), Ordering::Relaxed);
+            }
+            let mut cycle = self.cycle.lock();
+            cycle.bytes_copied += size;
+            cycle.surviving_objects += 1;
+        }
+        value.color.store(GC_GREY, Ordering::Release);
+        self.worklist.push(GcValue { value: object });
+    }
+
+    fn flip(&self) {
+        let mut x = self.to_space.lock();
+
+        let mut y = self.from_space.lock();
+        // pinned objects were not copied, their pages are moved to the new from-space as a whole. Objects
+        // unpinned while copying was in progress have no guards left, they are forgotten after their pages are kept.
+        let mut pinned = self.pinned.lock();
+        let kept = y.take_pages(|page| {
+            pinned
+                .keys()
+                .any(|object| *object >= page.data.to_usize() && *object < page.limit.to_usize())
+        });
+        pinned.retain(|_, count| *count != 0);
+        y.reset_pages();
+        if self.poison_evacuated() {
+            y.poison();
+        }
+        if self.config.protect_evacuated {
+            y.protect(self.epoch() + 1, false);
+        }
+        std::mem::swap(&mut *x, &mut *y);
+        y.adopt_pages(kept);
+        y.size_limit = self.config.size_limit(y.allocated_size);
+    }
+    fn flip_colours(&self) {
+        let white = self.white.load(Ordering::Relaxed);
+        let black = self.black.load(Ordering::Relaxed);
+        self.white.store(black, Ordering::Relaxed);
+        self.black.store(white, Ordering::Relaxed);
+    }
+    /// Calls `f` with live roots of `mutators` and of threads that were detached with live `Rooted`s, releases
+    /// entries of dropped `Rooted`s. Must be called while the world is stopped.
+    fn for_each_root(
+        &self,
+        mutators: &[Arc<MutatorThread>],
+        mut f: impl FnMut(&mut dyn RootedTrait),
+    ) {
+        let mut visit = |root: &*mut (dyn RootedTrait + 'static)| unsafe {
+            if (**root).is_rooted() {
+                f(&mut **root);
+                true
             } else {
-                std::thread::sleep(std::time::Duration::from_nanos(10));
+                let _ = Box::from_raw(*root);
+                false
             }
-            attempt += 1;
+        };
+        for thread in mutators.iter() {
+            thread.rootset.borrow_mut().retain(&mut visit);
         }
-        if HEAP.state.load(Ordering::Relaxed) == GC_TERMINATE {
-            return;
+        self.detached_roots.lock().retain(&mut visit);
+    }
+
+    /// Updates roots and finalizable objects to point to their copies, queues finalizers of objects that were not
+    /// copied. Slots of heap objects are relocated by GC workers when they scan them.
+    fn update_references(&self, mutators: &[Arc<MutatorThread>]) {
+        self.for_each_root(mutators, |root| unsafe {
+            let object = root.inner();
+            root.relocate(
+                Address::from_ptr(object as *const u8),
+                Address::from_ptr(forwarded(object) as *const u8),
+            );
+        });
+        let white = self.white.load(Ordering::Relaxed);
+        let mut dead = vec![];
+        self.weak_handles.lock().retain_mut(|item| unsafe {
+            if (&**item).color.load(Ordering::Relaxed) == white {
+                dead.push(finalizer(*item));
+                false
+            } else {
+                *item = forwarded(*item);
+                true
+            }
+        });
+        self.deferred_cleanups.lock().append(&mut dead);
+        let relocated = |object: &HeapInner<u8>| {
+            if object.color.load(Ordering::Relaxed) == white {
+                None
+            } else {
+                Some(object.fwdptr())
+            }
+        };
+        self.update_weak_refs(relocated);
+        self.update_ephemerons(relocated);
+        self.deferred_cleanups
+            .lock()
+            .append(&mut self.registrations.lock().update(relocated));
+    }
+
+    /// Greys objects written by mutators in current cycle again, so slots stored after they were scanned point to
+    /// copies once they are rescanned. Must be called while the world is stopped.
+    fn rescan_written(&self) {
+        let black = self.black.load(Ordering::Relaxed);
+        while let Ok(object) = self.written.pop() {
+            let value = object.value();
+            value.flags.fetch_and(!FLAG_WRITTEN, Ordering::Relaxed);
+            // white objects are dead or copied later, grey objects are already in the worklist.
+            if value
+                .color
+                .compare_exchange(black, GC_GREY, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+            {
+                self.worklist.push(object);
+            }
         }
+    }
 
-        GlobalHeap::collect_impl();
+    pub(crate) fn register_weak(&self, target: Address) -> Arc<WeakSlot> {
+        let slot = Arc::new(WeakSlot {
+            target: AtomicUsize::new(target.to_usize()),
+        });
+        self.weak_refs.lock().push(slot.clone());
+        slot
     }
-}
 
-lazy_static::lazy_static! {
-    pub static ref HEAP: GlobalHeap = {
-        std::thread::spawn(|| collect_routine());
-        GlobalHeap::new()
-    };
+    /// Updates weak references to new locations of their targets returned by `update`, clears them if it returns `None`.
+    /// Slots of dropped weak references are removed.
+    fn update_weak_refs(&self, mut update: impl FnMut(&HeapInner<u8>) -> Option<Address>) {
+        self.weak_refs.lock().retain(|slot| {
+            let target = slot.target.load(Ordering::Relaxed);
+            if target == 0 || Arc::strong_count(slot) == 1 {
+                return false;
+            }
+            // header has the same layout for all objects.
+            let object = unsafe { &*(target as *const HeapInner<u8>) };
+            match update(object) {
+                Some(address) => {
+                    slot.target.store(address.to_usize(), Ordering::Release);
+                    true
+                }
+                None => {
+                    slot.target.store(0, Ordering::Release);
+                    false
+                }
+            }
+        });
+    }
+    /// Pins object of `root` so it's not moved until it is unpinned, returns its address. Young objects are
+    /// promoted first.
+    pub(crate) fn pin(&self, root: &dyn HeapTrait) -> *mut HeapInner<dyn Trace> {
+        unsafe {
+            if !(*forwarded(root.inner())).is_old() {
+                self.minor_collect_with(true);
+            }
+            let object = root.inner();
+            debug_assert!((*forwarded(object)).is_old());
+            let mut pinned = self.pinned.lock();
+            // GC worker checks the flag after it claims object, so either it doesn't copy object or copy is
+            // finished and pinned here.
+            (*object).flags.fetch_or(FLAG_PINNED, Ordering::SeqCst);
+            while (*object).color.load(Ordering::SeqCst) == GC_BUSY {
+                std::thread::yield_now();
+            }
+            let object = forwarded(object);
+            (*object).flags.fetch_or(FLAG_PINNED, Ordering::SeqCst);
+            *pinned.entry(object as *mut u8 as usize).or_insert(0) += 1;
+            object
+        }
+    }
+
+    pub(crate) fn unpin(&self, object: *mut HeapInner<dyn Trace>) {
+        let mut pinned = self.pinned.lock();
+        let address = object as *mut u8 as usize;
+        // pinned objects are forgotten on shutdown.
+        if let Some(count) = pinned.get_mut(&address) {
+            *count -= 1;
+            if *count == 0 {
+                // object might be already marked in place, so its page is kept until the flip.
+                if self.state.load(Ordering::Acquire) != GC_COPYING {
+                    pinned.remove(&address);
+                }
+                unsafe {
+                    (*object).flags.fetch_and(!FLAG_PINNED, Ordering::SeqCst);
+                }
+            }
+        }
+    }
+
+    /// Returns stores without write barrier detected since previous call, see `HeapConfig::check_barriers`.
+    pub fn missing_barriers(&self) -> Vec<MissingBarrier> {
+        std::mem::take(&mut *self.missing_barriers.lock())
+    }
+
+    /// Compares references of scanned objects with their snapshots, reference that is not in the snapshot was
+    /// stored after object was scanned and object was not greyed again. Must be called before flip.
+    fn check_barriers(&self) {
+        let snapshots = std::mem::take(&mut *self.snapshots.lock());
+        if snapshots.is_empty() {
+            return;
+        }
+        // references are compared by current locations of their targets.
+        let location = |object: usize| unsafe { (*(object as *const HeapInner<u8>)).fwdptr() };
+        let epoch = self.epoch() + 1;
+        let mut missing = self.missing_barriers.lock();
+        for (object, snapshot) in snapshots.into_values() {
+            let object = unsafe { &*object };
+            let snapshot = snapshot.into_iter().map(location).collect::<Vec<_>>();
+            object.value.trace(&mut Tracer::new(&mut |reference| {
+                let target = unsafe { (*reference).inner() };
+                if snapshot.contains(&location(target as *mut u8 as usize)) {
+                    return;
+                }
+                let barrier = MissingBarrier {
+                    epoch,
+                    object: object.fwdptr(),
+                    object_type: object.value.type_name(),
+                    target_type: unsafe { (*target).value.type_name() },
+                };
+                log::error!("{}", barrier);
+                missing.push(barrier);
+            }));
+        }
+    }
+
+    pub(crate) fn register_cleanup(&self, target: Address, cleanup: Cleanup) -> RegistrationToken {
+        self.registrations.lock().add(target, cleanup)
+    }
+
+    pub(crate) fn unregister_cleanup(&self, token: RegistrationToken) -> bool {
+        self.registrations.lock().remove(token)
+    }
+
+    /// Runs finalizers of collected objects and cleanups of objects registered in `FinalizationRegistry`s on current
+    /// thread, returns number of cleanups that were run. Used when `HeapConfig::finalizer_thread` is disabled.
+    pub fn run_cleanups(&self) -> usize {
+        self.cleanups.run_pending()
+    }
+
+    pub(crate) fn register_ephemerons(&self, table: Arc<EphemeronTable>) {
+        self.ephemerons.lock().push(table);
+    }
+
+    pub(crate) fn is_copying(&self) -> bool {
+        self.state.load(Ordering::Acquire) == GC_COPYING
+    }
+
+    /// Calls `f` with key and value of each ephemeron.
+    fn for_each_ephemeron(&self, mut f: impl FnMut(&HeapInner<u8>, *mut HeapInner<dyn Trace>)) {
+        for table in self.ephemerons.lock().iter() {
+            for (key, value) in table.entries.lock().iter() {
+                f(unsafe { &*(*key as *const HeapInner<u8>) }, *value);
+            }
+        }
+    }
+
+    /// Rehashes ephemerons by new locations of their keys returned by `update` and moves values to their
+    /// copies, removes entries for which `update` returns `None`. Tables of dropped maps are removed.
+    fn update_ephemerons(&self, mut update: impl FnMut(&HeapInner<u8>) -> Option<Address>) {
+        self.ephemerons.lock().retain(|table| {
+            if Arc::strong_count(table) == 1 {
+                return false;
+            }
+            let mut entries = table.entries.lock();
+            *entries = std::mem::take(&mut *entries)
+                .into_iter()
+                .filter_map(|(key, value)| {
+                    let key = update(unsafe { &*(key as *const HeapInner<u8>) })?;
+                    Some((key.to_usize(), unsafe { forwarded(value) }))
+                })
+                .collect();
+            true
+        });
+    }
+
+    /// Pushes values of ephemerons whose keys were reached to worklist, returns false if there were none.
+    fn trace_ephemerons(&self) -> bool {
+        let white = self.white.load(Ordering::Relaxed);
+        let mut found = false;
+        self.for_each_ephemeron(|key, value| unsafe {
+            if key.color.load(Ordering::Acquire) != white
+                && (*value).color.load(Ordering::Acquire) == white
+            {
+                self.worklist.push(GcValue { value });
+                found = true;
+            }
+        });
+        found
+    }
+
+    /// Releases large objects that were not marked and finalized ones, must be called before colours are flipped.
+    /// Objects waiting for their finalizers are kept.
+    fn sweep_large_objects(&self) {
+        let white = self.white.load(Ordering::Relaxed);
+        let mut large_space = self.large_space.lock();
+        large_space.sweep(|object| unsafe {
+            let object = &*object.to_ptr::<HeapInner<u8>>();
+            let flags = object.flags.load(Ordering::Acquire);
+            flags & FLAG_FINALIZED != 0
+                || (flags & FLAG_FINALIZING == 0 && object.color.load(Ordering::Relaxed) == white)
+        });
+        large_space.size_limit = self.config.size_limit(large_space.allocated_size);
+    }
+    fn collect_impl(&self) {
+        let cycle = self.cycle.lock().clone();
+        self.notify(GcEvent::CopyStart, &cycle);
+        // copy objects
+        let start = Instant::now();
+        self.process_grey();
+        let copy_time = start.elapsed();
+        let (mut cycle, pause) = self.stop_the_world(|mutators| {
+            self.retire_tlabs(mutators);
+            self.rescan_written();
+            // objects greyed by mutators after worklist was drained.
+            self.process_grey();
+            self.check_barriers();
+            self.update_references(mutators);
+            self.sweep_large_objects();
+            log::trace!("GC Worker: flip");
+            self.flip();
+            self.flip_colours();
+            // next cycle might start as soon as write barriers are disabled.
+            let cycle = std::mem::take(&mut *self.cycle.lock());
+            self.notify(GcEvent::Flip, &cycle);
+            // disable write barriers
+            self.state.store(GC_NONE, Ordering::Release);
+            HEAPS_COPYING.fetch_sub(1, Ordering::AcqRel);
+            cycle
+        });
+        cycle.epoch = self.epoch() + 1;
+        cycle.copy_time = copy_time;
+        cycle.add_pause(pause);
+        self.finish_cycle(cycle);
+        {
+            let _lock = self.cycle_lock.lock();
+            self.epoch.fetch_add(1, Ordering::AcqRel);
+            self.cycle_cond.notify_all();
+        }
+        self.verify_if_enabled();
+    }
+
+    /// Allocates object.
+    ///
+    /// # Panics
+    /// Panics if heap is out of memory, see `try_allocate`.
+    pub fn allocate<T: Trace + 'static>(&self, value: T, finalize: bool) -> *mut HeapInner<T> {
+        match self.try_allocate(value, finalize) {
+            Ok(raw) => raw,
+            Err(error) => panic!("{}", error),
+        }
+    }
+
+    /// Allocates object, if heap reached `HeapConfig::max_heap_size` runs emergency full collection and
+    /// returns `AllocError` if there is still no memory after it.
+    pub fn try_allocate<T: Trace + 'static>(
+        &self,
+        value: T,
+        finalize: bool,
+    ) -> Result<*mut HeapInner<T>, AllocError> {
+        assert_ne!(
+            self.state.load(Ordering::Relaxed),
+            GC_TERMINATE,
+            "allocation in heap that was shut down"
+        );
+        let size = std::mem::size_of::<HeapInner<T>>();
+        let large = size >= self.config.large_object_size;
+        // While copying is in progress new objects are allocated in to-space so they survive the flip,
+        // they are also scanned by collector since they might contain pointers to not yet copied objects.
+        let mut copying = self.state.load(Ordering::Acquire) == GC_COPYING;
+        let (memory, young) = match self.allocate_memory(size, large, copying) {
+            Ok(memory) => memory,
+            Err(_) => {
+                log::debug!("Heap limit reached, emergency collection");
+                self.collect_and_wait();
+                copying = self.state.load(Ordering::Acquire) == GC_COPYING;
+                self.allocate_memory(size, large, copying)?
+            }
+        };
+        log::trace!("Allocate {:p}", memory.to_ptr::<u8>());
+        unsafe {
+            let raw = memory.to_mut_ptr::<HeapInner<T>>();
+            raw.write(HeapInner {
+                forward: AtomicUsize::new(raw as usize),
+                color: AtomicU8::new(if copying {
+                    GC_GREY
+                } else {
+                    self.white.load(Ordering::Relaxed)
+                }),
+                flags: AtomicU8::new(if young {
+                    0
+                } else if large {
+                    FLAG_OLD | FLAG_LARGE
+                } else {
+                    FLAG_OLD
+                }),
+                age: 0,
+                value,
+            });
+            if finalize && young {
+                self.young_handles.lock().push(raw);
+            } else if finalize {
+                self.weak_handles.lock().push(raw);
+            }
+            if !young && !copying && self.config.generational() {
+                // object was initialized with pointers that might point to young objects.
+                self.remember(raw);
+            }
+            if copying {
+                self.worklist.push(GcValue { value: raw });
+            }
+
+            Ok(raw)
+        }
+    }
+
+    /// Allocates memory for object of `size` bytes, returns its address and whether it's in nursery.
+    fn allocate_memory(
+        &self,
+        size: usize,
+        large: bool,
+        copying: bool,
+    ) -> Result<(Address, bool), AllocError> {
+        let max = self.config.max_heap_size;
+        if large {
+            let mut gc = false;
+            let memory = self
+                .large_space
+                .lock()
+                .try_allocate(size, &mut gc, max)
+                .ok_or(AllocError { size })?;
+            self.allocated_bytes.fetch_add(size, Ordering::Relaxed);
+            if gc && !copying {
+                self.needs_gc.store(true, Ordering::Relaxed);
+            }
+            return Ok((memory, false));
+        }
+        if let Some(memory) = self.allocate_small(size, copying)? {
+            return Ok((memory, !copying && self.config.generational()));
+        }
+        // nursery is full.
+        let mut gc = false;
+        let memory = self
+            .from_space
+            .lock()
+            .try_allocate(size, &mut gc, max)
+            .ok_or(AllocError { size })?;
+        self.allocated_bytes.fetch_add(size, Ordering::Relaxed);
+        if gc {
+            self.needs_gc.store(true, Ordering::Relaxed);
+        }
+        Ok((memory, false))
+    }
+
+    /// Returns space new small objects are allocated in.
+    fn allocation_space(&self) -> &parking_lot::Mutex<Space> {
+        if self.state.load(Ordering::Acquire) == GC_COPYING {
+            &self.to_space
+        } else if self.config.generational() {
+            &self.nursery
+        } else {
+            &self.from_space
+        }
+    }
+
+    /// Allocates memory in TLAB of current thread, TLAB is refilled from `allocation_space`.
+    ///
+    /// Returns `None` and requests minor collection if nursery is full.
+    fn allocate_small(&self, size: usize, copying: bool) -> Result<Option<Address>, AllocError> {
+        THREAD.with(|thread| {
+            let thread = thread.borrow();
+            // TLAB belongs to heap the thread is attached to.
+            let attached = thread
+                .heap
+                .borrow()
+                .as_ref()
+                .is_some_and(|heap| std::ptr::eq(&**heap, self));
+            let mut tlab = if attached {
+                Some(thread.tlab.borrow_mut())
+            } else {
+                None
+            };
+            if let Some(memory) = tlab.as_mut().and_then(|tlab| tlab.allocate(size)) {
+                return Ok(Some(memory));
+            }
+            let young = !copying && self.config.generational();
+            let tlab_size = self.config.tlab_size;
+            let max = self.config.max_heap_size;
+            let mut space = self.allocation_space().lock();
+            let mut gc = false;
+            let memory = match tlab {
+                Some(ref mut tlab)
+                    if size <= tlab_size / 2
+                        && (!young || space.may_allocate_in_current(tlab_size)) =>
+                {
+                    self.retire_tlab(tlab, &mut space);
+                    if !tlab.try_fill(&mut space, tlab_size, &mut gc, max) {
+                        return Err(AllocError { size });
+                    }
+                    tlab.allocate(size).unwrap()
+                }
+                _ => {
+                    if young && !space.may_allocate_in_current(size) {
+                        self.needs_minor_gc.store(true, Ordering::Relaxed);
+                        return Ok(None);
+                    }
+                    let memory = space
+                        .try_allocate(size, &mut gc, max)
+                        .ok_or(AllocError { size })?;
+                    self.allocated_bytes.fetch_add(size, Ordering::Relaxed);
+                    memory
+                }
+            };
+            if young && !space.may_allocate_in_current(tlab_size) {
+                self.needs_minor_gc.store(true, Ordering::Relaxed);
+            }
+            if gc && !copying && !young {
+                self.needs_gc.store(true, Ordering::Relaxed);
+            }
+            Ok(Some(memory))
+        })
+    }
+
+    /// Gives unused memory of TLABs back to `allocation_space`, must be called while the world is stopped
+    /// before allocation space changes.
+    fn retire_tlabs(&self, mutators: &[Arc<MutatorThread>]) {
+        let mut space = self.allocation_space().lock();
+        for thread in mutators.iter() {
+            self.retire_tlab(&mut thread.tlab.borrow_mut(), &mut space);
+        }
+    }
+
+    /// Retires TLAB and counts memory allocated from it, so allocated bytes only grow.
+    fn retire_tlab(&self, tlab: &mut LocalAllocationBuffer, space: &mut Space) {
+        self.allocated_bytes
+            .fetch_add(tlab.used(), Ordering::Relaxed);
+        tlab.retire(space);
+    }
+
+    /// Copies objects from worklist and values of ephemerons with reachable keys until no new objects are reached.
+    fn process_grey(&self) {
+        loop {
+            self.copy_objects();
+            if !self.trace_ephemerons() {
+                break;
+            }
+        }
+    }
+
+    /// Copies objects from worklist using `HeapConfig::collector_threads` threads until there is no work left.
+    fn copy_objects(&self) {
+        let threads = std::cmp::max(self.config.collector_threads, 1);
+        let mut locals = (0..threads)
+            .map(|_| deque::Worker::new_lifo())
+            .collect::<Vec<_>>();
+        let stealers = locals
+            .iter()
+            .map(|local| local.stealer())
+            .collect::<Vec<_>>();
+        let main = locals.pop().unwrap();
+        let phase = Arc::new(CopyPhase {
+            locals: parking_lot::Mutex::new(locals),
+            stealers,
+            active: AtomicUsize::new(threads),
+            lab_size: self.to_space.lock().page_size / 4,
+            running: parking_lot::Mutex::new(threads - 1),
+            done: parking_lot::Condvar::new(),
+        });
+        if threads > 1 {
+            let mut helpers = self.helpers.lock();
+            helpers.phases += 1;
+            helpers.phase = Some(phase.clone());
+            self.helpers_cond.notify_all();
+        }
+        phase.worker(self, main).run();
+        let mut running = phase.running.lock();
+        while *running != 0 {
+            phase.done.wait(&mut running);
+        }
+    }
+}
+
+/// State of GC helper threads, each helper copies objects in every phase started by `GlobalHeap::copy_objects`.
+#[derive(Default)]
+struct CopyHelpers {
+    /// Number of started phases.
+    phases: usize,
+    phase: Option<Arc<CopyPhase>>,
+    terminate: bool,
+}
+
+/// Work queues of one `GlobalHeap::copy_objects` call, shared by collector thread and GC helper threads.
+struct CopyPhase {
+    /// Local queues of helpers, each helper takes one.
+    locals: parking_lot::Mutex<Vec<deque::Worker<GcValue>>>,
+    stealers: Vec<deque::Stealer<GcValue>>,
+    /// Number of workers that are not out of work.
+    active: AtomicUsize,
+    lab_size: usize,
+    /// Number of helpers that haven't finished this phase yet.
+    running: parking_lot::Mutex<usize>,
+    done: parking_lot::Condvar,
+}
+
+impl CopyPhase {
+    fn worker<'a>(&'a self, heap: &'a GlobalHeap, local: deque::Worker<GcValue>) -> GcWorker<'a> {
+        GcWorker {
+            heap,
+            local,
+            stealers: &self.stealers,
+            active: &self.active,
+            lab: LocalAllocationBuffer::empty(),
+            lab_size: self.lab_size,
+            bytes_copied: 0,
+            surviving_objects: 0,
+        }
+    }
+}
+
+/// Thread copying objects, takes work from its local queue, global worklist or steals it from other workers.
+struct GcWorker<'a> {
+    heap: &'a GlobalHeap,
+    local: deque::Worker<GcValue>,
+    stealers: &'a [deque::Stealer<GcValue>],
+    /// Number of workers that are not out of work.
+    active: &'a AtomicUsize,
+    lab: LocalAllocationBuffer,
+    lab_size: usize,
+    bytes_copied: usize,
+    surviving_objects: usize,
+}
+
+impl<'a> GcWorker<'a> {
+    fn run(mut self) {
+        loop {
+            while let Some(value) = self.find_work() {
+                self.visit(value.value());
+            }
+            self.active.fetch_sub(1, Ordering::SeqCst);
+            loop {
+                if self.has_visible_work() {
+                    self.active.fetch_add(1, Ordering::SeqCst);
+                    break;
+                }
+                if self.active.load(Ordering::SeqCst) == 0 {
+                    self.lab.retire(&mut self.heap.to_space.lock());
+                    let mut cycle = self.heap.cycle.lock();
+                    cycle.bytes_copied += self.bytes_copied;
+                    cycle.surviving_objects += self.surviving_objects;
+                    return;
+                }
+                std::thread::yield_now();
+            }
+        }
+    }
+
+    /// Returns object to scan, objects from global worklist are copied or greyed first.
+    fn find_work(&mut self) -> Option<GcValue> {
+        if let Some(value) = self.local.pop() {
+            return Some(value);
+        }
+        while let Ok(value) = self.heap.worklist.pop() {
+            self.process(value);
+            if let Some(value) = self.local.pop() {
+                return Some(value);
+            }
+        }
+        for stealer in self.stealers.iter() {
+            loop {
+                match stealer.steal() {
+                    deque::Steal::Success(value) => return Some(value),
+                    deque::Steal::Empty => break,
+                    deque::Steal::Retry => continue,
+                }
+            }
+        }
+        None
+    }
+
+    fn has_visible_work(&self) -> bool {
+        !self.heap.worklist.is_empty() || self.stealers.iter().any(|stealer| !stealer.is_empty())
+    }
+
+    fn allocate(&mut self, size: usize) -> Address {
+        if size > self.lab_size / 2 {
+            return self.heap.to_space.lock().allocate(size, &mut false);
+        }
+        if let Some(memory) = self.lab.allocate(size) {
+            return memory;
+        }
+        self.lab
+            .refill(&mut self.heap.to_space.lock(), self.lab_size, &mut false);
+        self.lab.allocate(size).unwrap()
+    }
+
+    /// Scans object, objects it refers to are copied and its slots are pointed to the copies.
+    fn visit(&mut self, value: &mut HeapInner<dyn Trace>) {
+        let mut snapshot = self.heap.config.check_barriers.then(Vec::new);
+        value.value.trace(&mut Tracer::new(&mut |slot| unsafe {
+            let item = (*slot).inner();
+            if let Some(snapshot) = snapshot.as_mut() {
+                snapshot.push(item as *mut u8 as usize);
+            }
+            let copy = self.copy(item);
+            if copy as *mut u8 != item as *mut u8 {
+                (*slot).relocate(
+                    Address::from_ptr(item as *const u8),
+                    Address::from_ptr(copy as *const u8),
+                );
+            }
+        }));
+        if let Some(snapshot) = snapshot {
+            let address = value as *mut HeapInner<dyn Trace> as *mut u8 as usize;
+            self.heap
+                .snapshots
+                .lock()
+                .insert(address, (value, snapshot));
+        }
+    }
+
+    /// Copies object from global worklist, object greyed by write barrier or allocated while copying is rescanned.
+    fn process(&mut self, value: GcValue) {
+        let black = self.heap.black.load(Ordering::Relaxed);
+        if value
+            .value()
+            .color
+            .compare_exchange(GC_GREY, black, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+        {
+            self.local.push(GcValue {
+                value: unsafe { forwarded(value.value) },
+            });
+        } else {
+            self.copy(value.value);
+        }
+    }
+
+    /// Copies white object and pushes the copy to local queue to be scanned, large and pinned objects are marked
+    /// in place. If other worker or mutator is copying the object waits until it's done. Returns current location
+    /// of the object.
+    fn copy(&mut self, object: *mut HeapInner<dyn Trace>) -> *mut HeapInner<dyn Trace> {
+        let white = self.heap.white.load(Ordering::Relaxed);
+        let black = self.heap.black.load(Ordering::Relaxed);
+        let value = unsafe { &*object };
+        loop {
+            let color = value.color.load(Ordering::Acquire);
+            if color == GC_BUSY {
+                std::thread::yield_now();
+            } else if color != white {
+                return unsafe { forwarded(object) };
+            } else if value.is_large() {
+                // large objects are marked in place.
+                if value
+                    .color
+                    .compare_exchange(white, black, Ordering::AcqRel, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    self.surviving_objects += 1;
+                    self.local.push(GcValue { value: object });
+                    return object;
+                }
+            } else if value
+                .color
+                .compare_exchange(white, GC_BUSY, Ordering::SeqCst, Ordering::Relaxed)
+                .is_ok()
+            {
+                break;
+            }
+        }
+        self.surviving_objects += 1;
+        if value.is_pinned() {
+            // pinned objects are marked in place, their pages are kept at flip.
+            value.color.store(black, Ordering::SeqCst);
+            self.local.push(GcValue { value: object });
+            return object;
+        }
+        let size = std::mem::size_of_val(value);
+        let hvalue = self.allocate(size);
+        unsafe {
+            std::ptr::copy_nonoverlapping(object as *const u8, hvalue.to_mut_ptr::<u8>(), size);
+        }
+        log::trace!(
+            "GC Worker: Copy {:p}->{:p}",
+            object,
+            hvalue.to_mut_ptr::<u8>()
+        );
+        value.set_fwdptr(hvalue);
+        let copy = unsafe { forwarded(object) };
+        unsafe {
+            (*copy).set_fwdptr(hvalue);
+            (*copy).color.store(black, Ordering::Relaxed);
+        }
+        self.bytes_copied += size;
+        value.color.store(black, Ordering::Release);
+        self.local.push(GcValue { value: copy });
+        copy
+    }
+}
+
+fn copy_helper_routine(heap: Arc<GlobalHeap>) {
+    let mut phases = 0;
+    loop {
+        let phase = {
+            let mut helpers = heap.helpers.lock();
+            while helpers.phases == phases && !helpers.terminate {
+                heap.helpers_cond.wait(&mut helpers);
+            }
+            if helpers.terminate {
+                return;
+            }
+            phases = helpers.phases;
+            helpers.phase.clone().unwrap()
+        };
+        let local = phase.locals.lock().pop().unwrap();
+        phase.worker(&heap, local).run();
+        *phase.running.lock() -= 1;
+        phase.done.notify_one();
+    }
+}
+
+fn collect_routine(heap: Arc<GlobalHeap>) {
+    loop {
+        {
+            let mut lock = heap.collector_lock.lock();
+            while heap.state.load(Ordering::Acquire) == GC_NONE {
+                heap.collector_cond.wait(&mut lock);
+            }
+        }
+        if heap.state.load(Ordering::Relaxed) == GC_TERMINATE {
+            return;
+        }
+
+        heap.collect_impl();
+    }
+}
+
+static DEFAULT_HEAP_INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+lazy_static::lazy_static! {
+    static ref DEFAULT_HEAP_CONFIG: parking_lot::Mutex<Option<HeapConfig>> = parking_lot::Mutex::new(None);
+    /// Default heap, used by `attach_current_thread`. Created with default configuration when used for the first time
+    /// unless it was configured by `cgc::init`.
+    pub static ref HEAP: Arc<GlobalHeap> = {
+        DEFAULT_HEAP_INITIALIZED.store(true, Ordering::Release);
+        GlobalHeap::new(DEFAULT_HEAP_CONFIG.lock().take().unwrap_or_default())
+    };
+}
+
+pub(crate) fn init_default_heap(config: HeapConfig) {
+    assert!(
+        !DEFAULT_HEAP_INITIALIZED.load(Ordering::Acquire),
+        "default heap is already initialized"
+    );
+    *DEFAULT_HEAP_CONFIG.lock() = Some(config);
+    lazy_static::initialize(&HEAP);
+}
+
+pub(crate) fn shutdown_default_heap() {
+    if DEFAULT_HEAP_INITIALIZED.load(Ordering::Acquire) {
+        HEAP.shutdown();
+    }
+}
+
+/// Fixtures shared by unit tests of the heap and modules built on it.
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+
+    /// Configuration of heap collected only when test asks for it.
+    pub(crate) fn manual() -> HeapConfig {
+        HeapConfig {
+            trigger: GcTrigger::Manual,
+            ..Default::default()
+        }
+    }
+
+    /// Configuration of manually collected heap with nursery, young objects are promoted after `promotion_age` minor
+    /// cycles.
+    pub(crate) fn generational(promotion_age: u8) -> HeapConfig {
+        HeapConfig {
+            nursery_size: 64 * 1024,
+            promotion_age,
+            ..manual()
+        }
+    }
+
+    /// Creates heap with `config` and attaches current thread to it.
+    pub(crate) fn attached(config: HeapConfig) -> Arc<GlobalHeap> {
+        let heap = GlobalHeap::new(config);
+        heap.attach_current_thread();
+        heap
+    }
+
+    /// Detaches current thread from `heap` and shuts it down.
+    pub(crate) fn finish(heap: &GlobalHeap) {
+        heap.detach_current_thread();
+        heap.shutdown();
+    }
+
+    /// Address of object rooted by `value`.
+    pub(crate) fn address_of<T: Trace>(value: &Rooted<T>) -> Address {
+        Address::from_ptr(Handle::from(value).inner as *const u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::*;
+    use super::*;
+
+    #[test]
+    fn test_separate_heaps() {
+        let a = attached(HeapConfig::default());
+        let x = mt_alloc(42, false);
+        a.detach_current_thread();
+
+        let b = attached(HeapConfig::default());
+        let y = mt_alloc(3, false);
+
+        assert!(a.from_space.lock().contains(address_of(&x)));
+        assert!(!b.from_space.lock().contains(address_of(&x)));
+        assert!(b.from_space.lock().contains(address_of(&y)));
+        assert_eq!(*x + *y, 45);
+        finish(&b);
+        a.shutdown();
+    }
+
+    #[test]
+    fn test_manual_trigger() {
+        let heap = attached(manual());
+        for i in 0..100_000 {
+            mt_alloc(i, false);
+        }
+        assert!(!heap.needs_gc.load(Ordering::Relaxed));
+        finish(&heap);
+    }
+
+    #[test]
+    fn test_automatic_trigger() {
+        let heap = attached(HeapConfig {
+            initial_heap_size: 64 * 1024,
+            ..Default::default()
+        });
+        assert_eq!(heap.config().trigger, GcTrigger::Growth(2));
+        let epoch = heap.epoch();
+        // allocations alone start cycles once heap grows over the threshold, collector finishes them concurrently.
+        let mut allocated = 0;
+        while heap.epoch() == epoch {
+            mt_alloc(allocated, false);
+            allocated += 1;
+            assert!(allocated < 10_000_000, "no cycle was started by allocation");
+        }
+        assert!(heap.stats().cycles > 0);
+        finish(&heap);
+    }
+
+    struct Finalized(Arc<AtomicBool>);
+
+    impl Traceable for Finalized {}
+    impl Finalizer for Finalized {
+        fn finalize(&mut self) {
+            self.0.store(true, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_collect_sync() {
+        let dead = Arc::new(AtomicBool::new(false));
+        let live = Arc::new(AtomicBool::new(false));
+        let heap = attached(manual());
+        mt_alloc(Finalized(dead.clone()), true);
+        let value = mt_alloc(Finalized(live.clone()), true);
+        let number = mt_alloc(42, false);
+
+        let epoch = heap.epoch();
+        heap.collect_sync();
+        assert_eq!(heap.epoch(), epoch + 1);
+        assert!(dead.load(Ordering::Relaxed));
+        assert!(!live.load(Ordering::Relaxed));
+        assert!(heap.from_space.lock().contains(address_of(&value)));
+        assert_eq!(*number, 42);
+
+        finish(&heap);
+        assert!(live.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_parallel_copying() {
+        let counter = Arc::new(AtomicUsize::new(0));
+        struct Counted(Arc<AtomicUsize>);
+        impl Traceable for Counted {}
+        impl Finalizer for Counted {
+            fn finalize(&mut self) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let heap = attached(HeapConfig {
+            collector_threads: 4,
+            ..manual()
+        });
+        let mut live = vec![];
+        for i in 0..10_000 {
+            let value = mt_alloc(Counted(counter.clone()), true);
+            if i % 2 == 0 {
+                live.push(value);
+            }
+        }
+        let numbers = (0..10_000).map(|i| mt_alloc(i, false)).collect::<Vec<_>>();
+        heap.collect_sync();
+        assert_eq!(counter.load(Ordering::Relaxed), 5_000);
+        for (i, number) in numbers.iter().enumerate() {
+            assert_eq!(**number, i);
+        }
+        // helpers are spawned once and reused by every copying phase.
+        assert!(heap.helpers.lock().phases >= 2);
+        assert_eq!(heap.helper_threads.lock().len(), 3);
+        finish(&heap);
+        assert!(heap.helper_threads.lock().is_empty());
+    }
+
+    struct ListNode {
+        value: usize,
+        next: Option<Handle<ListNode>>,
+    }
+
+    impl Traceable for ListNode {
+        fn trace_with(&self, tracer: &mut Tracer) {
+            self.next.trace_with(tracer);
+        }
+    }
+    impl Finalizer for ListNode {}
+
+    fn list_node() -> Rooted<ListNode> {
+        mt_alloc(
+            ListNode {
+                value: 0,
+                next: None,
+            },
+            false,
+        )
+    }
+
+    #[test]
+    fn test_write_while_copying() {
+        let heap = attached(manual());
+        let barrier = Arc::new(std::sync::Barrier::new(2));
+        let copy_start = barrier.clone();
+        heap.add_callback(GcEvent::CopyStart, move |_| {
+            copy_start.wait();
+            copy_start.wait();
+        });
+        let mut node = list_node();
+        let address = address_of(&node);
+        let epoch = heap.epoch();
+        heap.collect();
+        // collector waits in callback before it copies anything.
+        barrier.wait();
+        node.value = 42;
+        // object was copied before the store, so worker can't copy it later and lose the store.
+        assert_ne!(unsafe { (*Handle::from(&node).inner).fwdptr() }, address);
+        barrier.wait();
+        heap.wait_for_cycle(epoch);
+        assert_eq!(node.value, 42);
+        assert_ne!(address_of(&node), address);
+        assert_eq!(heap.verify(), Ok(1));
+        finish(&heap);
+    }
+
+    #[test]
+    fn test_concurrent_mutators() {
+        let heap = attached(HeapConfig {
+            collector_threads: 3,
+            ..Default::default()
+        });
+        let mutators = (0..3)
+            .map(|_| {
+                crate::thread::spawn(|| {
+                    let head = list_node();
+                    let mut tail = mt_root(Handle::from(&head));
+                    for _ in 1..200 {
+                        let node = list_node();
+                        crate::write_barrier(&Handle::from(&tail));
+                        tail.next = Some(Handle::from(&node));
+                        tail = node;
+                    }
+                    // garbage allocated between passes starts cycles, nodes are copied while they are updated.
+                    for pass in 1..=2000 {
+                        let mut sum = 0;
+                        let mut node = Some(Handle::from(&head));
+                        while let Some(mut current) = node {
+                            crate::write_barrier(&current);
+                            current.value += 1;
+                            sum += current.value;
+                            node = current.next;
+                        }
+                        assert_eq!(sum, pass * 200);
+                        for value in 0..20 {
+                            mt_alloc(value, false);
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        // joining thread doesn't reach safepoints.
+        heap.detach_current_thread();
+        for mutator in mutators {
+            mutator.join().unwrap();
+        }
+        assert!(heap.stats().cycles > 0);
+        heap.shutdown();
+    }
+
+    #[test]
+    fn test_roots_outlive_detach() {
+        let finalized = Arc::new(AtomicBool::new(false));
+        let heap = attached(manual());
+        let value = mt_alloc(Finalized(finalized.clone()), true);
+        let number = mt_alloc(42, false);
+        let address = address_of(&number);
+        heap.detach_current_thread();
+        assert!(THREAD.with(|thread| thread.borrow().rootset.borrow().is_empty()));
+
+        heap.collect_sync();
+        assert!(!finalized.load(Ordering::Relaxed));
+        assert!(heap.from_space.lock().contains(address_of(&number)));
+        assert_ne!(address_of(&number), address);
+        assert_eq!(*number, 42);
+        drop(value);
+        drop(number);
+        heap.collect_sync();
+        assert!(finalized.load(Ordering::Relaxed));
+        assert!(heap.detached_roots.lock().is_empty());
+        heap.shutdown();
+    }
+
+    #[test]
+    fn test_shutdown_finalizes_live_objects() {
+        let finalized = Arc::new(AtomicBool::new(false));
+        let heap = GlobalHeap::new(HeapConfig::default());
+        heap.attach_current_thread();
+        let _value = mt_alloc(Finalized(finalized.clone()), true);
+        heap.detach_current_thread();
+
+        heap.shutdown();
+        assert!(finalized.load(Ordering::Relaxed));
+        assert!(heap.collector.lock().is_none());
+        assert!(heap.from_space.lock().pages.is_empty());
+        // second shutdown does nothing.
+        heap.shutdown();
+    }
+
+    #[test]
+    fn test_tlab() {
+        let heap = attached(manual());
+        let tlab = || THREAD.with(|thread| thread.borrow().tlab.borrow().top);
+        let x = mt_alloc(1usize, false);
+        let y = mt_alloc(2usize, false);
+        // objects are bump allocated from the same buffer.
+        let size = std::mem::size_of::<HeapInner<usize>>();
+        assert_eq!(address_of(&y), address_of(&x).offset(size));
+        assert_eq!(tlab(), address_of(&y).offset(size));
+
+        heap.collect_sync();
+        assert!(tlab().is_null());
+        assert_eq!(heap.from_space.lock().allocated_size, 2 * size);
+        assert_eq!(*x + *y, 3);
+        mt_alloc(3usize, false);
+        heap.detach_current_thread();
+        assert!(tlab().is_null());
+        heap.shutdown();
+    }
+
+    #[test]
+    fn test_weak() {
+        let heap = attached(manual());
+        let live = mt_alloc(42, false);
+        let weak_live = Weak::from(&live);
+        let weak_dead = Weak::from(&mt_alloc(3, false));
+        let dropped = Weak::from(&live);
+        drop(dropped);
+        assert_eq!(heap.weak_refs.lock().len(), 3);
+        assert_eq!(*weak_dead.upgrade().unwrap(), 3);
+
+        heap.collect_sync();
+        assert!(!weak_dead.is_alive());
+        assert!(weak_dead.upgrade().is_none());
+        let upgraded = weak_live.upgrade().unwrap();
+        assert_eq!(address_of(&upgraded), address_of(&live));
+        assert_eq!(*upgraded, 42);
+        assert_eq!(heap.weak_refs.lock().len(), 1);
+        finish(&heap);
+        assert!(!weak_live.is_alive());
+    }
+
+    #[test]
+    fn test_promotion() {
+        let dead = Arc::new(AtomicBool::new(false));
+        let heap = attached(generational(2));
+        let value = mt_alloc(42, false);
+        mt_alloc(Finalized(dead.clone()), true);
+        assert!(heap.nursery.lock().contains(address_of(&value)));
+
+        heap.minor_collect();
+        assert!(dead.load(Ordering::Relaxed));
+        assert!(heap.nursery.lock().contains(address_of(&value)));
+        assert!(!heap.from_space.lock().contains(address_of(&value)));
+
+        heap.minor_collect();
+        assert!(heap.from_space.lock().contains(address_of(&value)));
+        assert_eq!(*value, 42);
+        let stats = heap.stats();
+        assert_eq!(stats.minor_cycles, 2);
+        assert_eq!(stats.surviving_objects, 1);
+        assert!(stats.history.iter().all(|cycle| cycle.minor));
+        let weak = Weak::from(&value);
+        let weak_dead = Weak::from(&mt_alloc(1, false));
+        heap.minor_collect();
+        assert!(weak_dead.upgrade().is_none());
+        assert_eq!(*weak.upgrade().unwrap(), 42);
+        finish(&heap);
+    }
+
+    /// Large object, finalizer stores address it was finalized at.
+    struct Blob([u8; 64 * 1024], Arc<AtomicUsize>);
+
+    impl Traceable for Blob {}
+    impl Finalizer for Blob {
+        fn finalize(&mut self) {
+            let address = self as *mut Blob as usize;
+            self.1.store(address, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_large_objects() {
+        let dead = Arc::new(AtomicUsize::new(0));
+        let live = Arc::new(AtomicUsize::new(0));
+        let heap = attached(manual());
+        mt_alloc(Blob([1; 64 * 1024], dead.clone()), true);
+        let value = mt_alloc(Blob([2; 64 * 1024], live.clone()), true);
+        let address = address_of(&value);
+        assert!(heap.large_space.lock().contains(address));
+        assert_eq!(heap.large_space.lock().objects.len(), 2);
+
+        heap.collect_sync();
+        // dead object is finalized in place, its page is released by the sweep that follows.
+        let finalized = Address::from(dead.load(Ordering::Relaxed));
+        assert!(heap.large_space.lock().contains(finalized));
+        assert_eq!(heap.large_space.lock().objects.len(), 2);
+        heap.collect_sync();
+        assert_eq!(live.load(Ordering::Relaxed), 0);
+        assert_eq!(address_of(&value), address);
+        assert_eq!(heap.large_space.lock().objects.len(), 1);
+        assert!(!heap.large_space.lock().contains(finalized));
+        assert!(value.0.iter().all(|byte| *byte == 2));
+        finish(&heap);
+        assert_ne!(live.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_heap_limit() {
+        let heap = attached(HeapConfig {
+            max_heap_size: 128 * 1024,
+            ..manual()
+        });
+        let finalized = Arc::new(AtomicUsize::new(0));
+        let error = match try_mt_alloc(Blob([0; 64 * 1024], finalized.clone()), true) {
+            Ok(_) => panic!("allocation over heap limit succeeded"),
+            Err(error) => error,
+        };
+        assert_eq!(error.size, std::mem::size_of::<HeapInner<Blob>>());
+        assert_eq!(heap.epoch(), 1);
+
+        let mut live = vec![];
+        while let Ok(value) = try_mt_alloc(live.len(), false) {
+            live.push(value);
+        }
+        assert!(heap.stats().committed_bytes <= 2 * 128 * 1024);
+        for (i, value) in live.iter().enumerate() {
+            assert_eq!(**value, i);
+        }
+        // emergency collection frees memory of dead objects.
+        live.clear();
+        assert_eq!(*try_mt_alloc(42, false).unwrap(), 42);
+        finish(&heap);
+    }
+
+    struct Node {
+        next: Option<Handle<usize>>,
+    }
+
+    impl Traceable for Node {
+        fn trace_with(&self, tracer: &mut Tracer) {
+            self.next.trace_with(tracer);
+        }
+    }
+    impl Finalizer for Node {}
+
+    #[test]
+    fn test_remembered_set() {
+        let heap = attached(generational(3));
+        let mut old = mt_alloc(Node { next: None }, false);
+        for _ in 0..3 {
+            heap.minor_collect();
+        }
+        assert!(heap.from_space.lock().contains(address_of(&old)));
+
+        old.next = Some(Handle::from(mt_alloc(7usize, false)));
+        crate::write_barrier(&Handle::from(&old));
+        // young object is reachable only from remembered set, old object stays there while it points to survivor.
+        heap.minor_collect();
+        heap.minor_collect();
+        for i in 0..100_000 {
+            mt_alloc(i, false);
+        }
+        assert_eq!(**old.next.as_ref().unwrap(), 7);
+        finish(&heap);
+    }
+
+    struct Buffer([u8; 256]);
+
+    impl Traceable for Buffer {}
+    impl Finalizer for Buffer {}
+
+    #[test]
+    fn test_pinning() {
+        let heap = attached(manual());
+        let value = mt_alloc(Buffer([7; 256]), false);
+        let moved = mt_alloc(1, false);
+        let pinned = value.pin();
+        let address = pinned.as_ptr();
+        let moved_address = address_of(&moved);
+        for _ in 0..2 {
+            heap.collect_sync();
+            mt_alloc(0, false);
+        }
+        assert_eq!(pinned.as_ptr(), address);
+        assert_eq!(value.get() as *const _, address);
+        assert!(heap.from_space.lock().contains(Address::from_ptr(address)));
+        assert_ne!(address_of(&moved), moved_address);
+        unsafe {
+            (*pinned.as_mut_ptr()).0[0] = 8;
+        }
+        assert_eq!(value.0[0], 8);
+        let other = value.pin();
+        drop(pinned);
+        heap.collect_sync();
+        assert_eq!(other.as_ptr(), address);
+        drop(other);
+        heap.collect_sync();
+        assert_ne!(value.get() as *const _, address);
+        assert_eq!(value.0[..2], [8, 7]);
+        finish(&heap);
+
+        let heap = attached(generational(2));
+        let young = mt_alloc(42, false);
+        let pinned = young.pin();
+        let address = pinned.as_ptr();
+        assert!(heap.from_space.lock().contains(address_of(&young)));
+        heap.minor_collect();
+        heap.collect_sync();
+        assert_eq!(pinned.as_ptr(), address);
+        assert_eq!(*young, 42);
+        finish(&heap);
+        drop(pinned);
+    }
+
+    #[test]
+    fn test_unpin_while_copying() {
+        let heap = attached(manual());
+        let value = mt_alloc(Buffer([7; 256]), false);
+        let pinned = value.pin();
+        let object = address_of(&value);
+        let epoch = heap.epoch();
+        heap.collect();
+        let black = heap.black.load(Ordering::Relaxed);
+        // this thread doesn't reach safepoint while it spins, so the cycle can't finish before object is marked.
+        while unsafe {
+            (*object.to_ptr::<HeapInner<u8>>())
+                .color
+                .load(Ordering::Acquire)
+        } != black
+        {
+            std::thread::yield_now();
+        }
+        drop(pinned);
+        heap.wait_for_cycle(epoch);
+        // object was marked in place, so its page is kept although it is not pinned anymore.
+        assert_eq!(address_of(&value), object);
+        assert!(heap.from_space.lock().contains(object));
+        let other = (0..100)
+            .map(|_| mt_alloc(Buffer([0; 256]), false))
+            .collect::<Vec<_>>();
+        heap.collect_sync();
+        assert_ne!(address_of(&value), object);
+        assert!(value.0.iter().all(|byte| *byte == 7));
+        drop(other);
+        finish(&heap);
+    }
+
+    #[test]
+    fn test_verify() {
+        let heap = attached(HeapConfig {
+            nursery_size: 64 * 1024,
+            verify: true,
+            ..manual()
+        });
+        let mut node = mt_alloc(Node { next: None }, false);
+        node.next = Some(Handle::from(mt_alloc(7usize, false)));
+        let value = mt_alloc(1usize, false);
+        assert_eq!(heap.verify(), Ok(3));
+        heap.minor_collect();
+        heap.minor_collect();
+        assert_eq!(heap.verify(), Ok(3));
+        heap.collect_sync();
+        assert_eq!(heap.verify(), Ok(3));
+        node.next = None;
+        heap.collect_sync();
+        assert_eq!(heap.verify(), Ok(2));
+
+        let object = unsafe { &*Handle::from(&value).inner };
+        let white = object.color.load(Ordering::Relaxed);
+        object.color.store(GC_GREY, Ordering::Relaxed);
+        let error = heap.verify().unwrap_err();
+        assert_eq!(error.object, address_of(&value));
+        assert!(error.reason.contains("colour"));
+        object.color.store(white, Ordering::Relaxed);
+        object.set_fwdptr(address_of(&node));
+        assert!(heap.verify().unwrap_err().reason.contains("forwarded"));
+        object.set_fwdptr(address_of(&value));
+        assert_eq!(heap.verify(), Ok(2));
+        finish(&heap);
+    }
+
+    struct Chain {
+        value: usize,
+        next: Option<Handle<Chain>>,
+        children: Vec<Handle<usize>>,
+    }
+
+    impl Traceable for Chain {
+        fn trace_with(&self, tracer: &mut Tracer) {
+            self.next.trace_with(tracer);
+            self.children.trace_with(tracer);
+        }
+    }
+    impl Finalizer for Chain {}
+
+    #[test]
+    fn test_slot_fixup() {
+        let heap = attached(HeapConfig {
+            collector_threads: 4,
+            verify: true,
+            ..manual()
+        });
+        let mut head = mt_alloc(
+            Chain {
+                value: 0,
+                next: None,
+                children: vec![],
+            },
+            true,
+        );
+        for value in 1..1000 {
+            let children = (0..4).map(|i| Handle::from(mt_alloc(value * 4 + i, false)));
+            head = mt_alloc(
+                Chain {
+                    value,
+                    next: Some(Handle::from(head)),
+                    children: children.collect(),
+                },
+                true,
+            );
+        }
+        for _ in 0..5 {
+            heap.collect_sync();
+            assert_eq!(heap.verify(), Ok(5000 - 4));
+            // slots point to objects directly, not through forwarding pointers of evacuated copies.
+            let mut node = Handle::from(&head);
+            let mut expected = 999;
+            loop {
+                assert_eq!(node.value, expected);
+                for (i, child) in node.children.iter().enumerate() {
+                    assert_eq!(**child, expected * 4 + i);
+                    assert_eq!(unsafe { (*child.inner).fwdptr() }, child.addr());
+                }
+                match node.next {
+                    Some(next) => {
+                        assert_eq!(unsafe { (*next.inner).fwdptr() }, next.addr());
+                        node = next;
+                    }
+                    None => break,
+                }
+                expected -= 1;
+            }
+            assert_eq!(expected, 0);
+            // buffer of `Vec` in scanned object is reallocated between cycles.
+            head.children = head.children.clone();
+            crate::write_barrier(&Handle::from(&head));
+        }
+        finish(&heap);
+    }
+
+    #[test]
+    fn test_slot_relocated_by_worker() {
+        let heap = attached(manual());
+        let mut node = mt_alloc(Node { next: None }, false);
+        node.next = Some(Handle::from(mt_alloc(7usize, false)));
+        let target = node.next.unwrap().addr();
+        let epoch = heap.epoch();
+        heap.collect();
+        // this thread doesn't reach safepoint while it spins, so only worker scanning the node can relocate slot.
+        while node.next.unwrap().addr() == target {
+            std::thread::yield_now();
+        }
+        assert!(heap.to_space.lock().contains(node.next.unwrap().addr()));
+        assert_eq!(*node.next.unwrap(), 7);
+        heap.wait_for_cycle(epoch);
+        assert_eq!(*node.next.unwrap(), 7);
+        finish(&heap);
+    }
+
+    #[test]
+    fn test_many_references() {
+        let heap = attached(generational(1));
+        let values = (0..1000)
+            .map(|i| Handle::from(mt_alloc(i, false)))
+            .collect::<Vec<_>>();
+        let object = mt_alloc(values, false);
+        heap.minor_collect();
+        heap.collect_sync();
+        assert_eq!(heap.verify(), Ok(1001));
+        for (i, value) in object.iter().enumerate() {
+            assert_eq!(**value, i);
+        }
+        finish(&heap);
+    }
+
+    #[test]
+    fn test_missing_barriers() {
+        let heap = attached(HeapConfig {
+            check_barriers: true,
+            ..manual()
+        });
+        let mut unchecked = mt_alloc(Node { next: None }, false);
+        let mut checked = mt_alloc(Node { next: None }, false);
+        let target = mt_alloc(7usize, false);
+        let epoch = heap.epoch();
+        heap.collect();
+        // flip needs this thread at safepoint, so cycle can't end before the stores.
+        for node in [&unchecked, &checked].iter() {
+            let object = Handle::from(*node).inner as *mut HeapInner<dyn Trace>;
+            while !heap
+                .snapshots
+                .lock()
+                .contains_key(&(unsafe { forwarded(object) } as *mut u8 as usize))
+            {
+                std::thread::yield_now();
+            }
+        }
+        unchecked.next = Some(Handle::from(&target));
+        checked.next = Some(Handle::from(&target));
+        crate::write_barrier(&Handle::from(&checked));
+        heap.wait_for_cycle(epoch);
+
+        let missing = heap.missing_barriers();
+        assert_eq!(missing.len(), 1);
+        assert_eq!(missing[0].epoch, epoch + 1);
+        assert_eq!(missing[0].object, address_of(&unchecked));
+        assert_eq!(missing[0].object_type, std::any::type_name::<Node>());
+        assert_eq!(missing[0].target_type, "usize");
+        assert!(heap.missing_barriers().is_empty());
+        finish(&heap);
+    }
+
+    #[test]
+    fn test_stress() {
+        let stress_heap = |stress| attached(HeapConfig { stress, ..manual() });
+        let heap = stress_heap(GcStress::EveryAllocation);
+        let value = mt_alloc(42usize, false);
+        assert_eq!(heap.epoch(), 1);
+        // unrooted handle kept across safepoint.
+        let stale = Handle::from(&value);
+        mt_alloc(0usize, false);
+        assert_eq!(heap.epoch(), 2);
+        assert_eq!(*value, 42);
+        assert_eq!(
+            unsafe { (*stale.inner).value },
+            usize::from_ne_bytes([POISON; 8])
+        );
+        mt_root(Handle::from(&value));
+        assert_eq!(heap.epoch(), 2);
+        finish(&heap);
+
+        let heap = stress_heap(GcStress::EveryAllocationAndRoot);
+        let value = mt_alloc(42usize, false);
+        let rooted = mt_root(Handle::from(&value));
+        assert_eq!(heap.epoch(), 2);
+        assert_eq!(*rooted, 42);
+        finish(&heap);
+
+        let heap = stress_heap(GcStress::EveryNthAllocation(3));
+        for i in 0..7usize {
+            mt_alloc(i, false);
+        }
+        assert_eq!(heap.epoch(), 2);
+        finish(&heap);
+
+        let heap = stress_heap(GcStress::EveryRoot);
+        let value = mt_alloc(42usize, false);
+        assert_eq!(heap.epoch(), 0);
+        let rooted = mt_root(Handle::from(&value));
+        assert_eq!(heap.epoch(), 1);
+        assert_eq!(*rooted, 42);
+        finish(&heap);
+
+        let heap = stress_heap(GcStress::EverySafepoint);
+        let value = mt_alloc(42usize, false);
+        assert!(crate::safepoint::safepoint_requested());
+        assert_eq!(heap.epoch(), 0);
+        let stale = Handle::from(&value);
+        crate::safepoint!();
+        assert_eq!(heap.epoch(), 1);
+        assert_eq!(*value, 42);
+        assert_eq!(
+            unsafe { (*stale.inner).value },
+            usize::from_ne_bytes([POISON; 8])
+        );
+        finish(&heap);
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn test_protect_evacuated() {
+        let heap = attached(HeapConfig {
+            nursery_size: 64 * 1024,
+            protect_evacuated: true,
+            ..manual()
+        });
+        let value = mt_alloc(42usize, false);
+        for _ in 0..2 {
+            heap.minor_collect();
+            heap.collect_sync();
+            mt_alloc(0usize, false);
+        }
+        assert_eq!(*value, 42);
+        assert_eq!(heap.verify(), Ok(1));
+        if std::env::var("CGC_STALE_POINTER").is_ok() {
+            let stale = Handle::from(&value);
+            heap.collect_sync();
+            // crashes the process.
+            println!("{}", unsafe { (*stale.inner).value });
+        }
+        finish(&heap);
+
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(&[
+                "--exact",
+                "heap::tests::test_protect_evacuated",
+                "--test-threads=1",
+            ])
+            .env("CGC_STALE_POINTER", "1")
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(!output.status.success());
+        assert!(stderr.contains("through stale pointer"), "{}", stderr);
+        assert!(
+            stderr.contains("evacuated by full collection in cycle 3"),
+            "{}",
+            stderr
+        );
+    }
 }
@@ -1,9 +1,37 @@
 pub mod api;
+pub mod config;
+pub mod events;
+pub mod finalization;
 pub mod heap;
 pub mod mem;
 pub mod safepoint;
 pub mod space;
+pub mod stats;
+pub mod thread;
 pub mod threads;
+pub mod weakmap;
+
+/// Initializes default heap `heap::HEAP` with `config` and starts its collector thread.
+///
+/// # Panics
+/// Panics if default heap is already initialized, either by previous `init` call or by using `HEAP`.
+pub fn init(config: config::HeapConfig) {
+    heap::init_default_heap(config);
+}
+
+/// Shuts down default heap: stops collector thread, runs finalizers of objects that need finalization and unmaps heap memory.
+///
+/// See `GlobalHeap::shutdown`.
+pub fn shutdown() {
+    heap::shutdown_default_heap();
+}
+
+/// Runs `f` in native region (see `threads::NativeRegion`), use it for blocking calls (I/O, locks, sleeping) so
+/// current thread doesn't stall collections of its heap while it waits. `f` must not access GC objects.
+pub fn enter_native<R>(f: impl FnOnce() -> R) -> R {
+    let _region = threads::NativeRegion::enter();
+    f()
+}
 
 /// Write barrier *must* be executed before store to some heap object happens.
 ///
@@ -11,7 +39,9 @@ pub mod threads;
 /// ## Where and when to use?
 /// You should place write barrier before store and write barrier is needed only when you store other GC value into GC value.
 pub fn write_barrier<T: api::HeapTrait + ?Sized>(src: &T) {
-    unsafe {
-        heap::write_barrier_impl(src.inner());
-    }
+    threads::THREAD.with(|thread| {
+        thread
+            .borrow()
+            .with_heap(|heap| unsafe { heap.write_barrier(src.inner()) })
+    });
 }
@@ -11,5 +11,5 @@ fn main() {
         println!("{}", *x + *y);
     }
     HEAP.collect();
-    std::thread::sleep(std::time::Duration::from_nanos(200000));
+    cgc::shutdown();
 }
@@ -49,8 +49,15 @@ pub fn memory_limit() -> usize {
 }
 
 #[cfg(target_family = "windows")]
-pub(crate) fn memory_limit() -> usize {
-    unimplemented!()
+pub fn memory_limit() -> usize {
+    use winapi::um::sysinfoapi::{GlobalMemoryStatusEx, MEMORYSTATUSEX};
+
+    unsafe {
+        let mut status: MEMORYSTATUSEX = std::mem::zeroed();
+        status.dwLength = std::mem::size_of::<MEMORYSTATUSEX>() as u32;
+        GlobalMemoryStatusEx(&mut status);
+        status.ullTotalPhys as usize
+    }
 }
 
 #[cfg(target_family = "unix")]
@@ -291,8 +298,13 @@ pub fn reserve_align(size: usize, align: usize) -> Address {
     aligned
 }
 
-#[cfg(target_family = "unix")]
 pub fn commit(size: usize, executable: bool) -> Address {
+    try_commit(size, executable).expect("committing memory failed")
+}
+
+/// Commits memory, returns `None` if OS can't provide it.
+#[cfg(target_family = "unix")]
+pub fn try_commit(size: usize, executable: bool) -> Option<Address> {
     debug_assert!(mem::is_page_aligned(size));
 
     let mut prot = libc::PROT_READ | libc::PROT_WRITE;
@@ -313,14 +325,15 @@ pub fn commit(size: usize, executable: bool) -> Address {
     };
 
     if ptr == libc::MAP_FAILED {
-        panic!("committing memory with mmap() failed");
+        return None;
     }
 
-    Address::from_ptr(ptr)
+    Some(Address::from_ptr(ptr))
 }
 
+/// Commits memory, returns `None` if OS can't provide it.
 #[cfg(target_family = "windows")]
-pub fn commit(size: usize, executable: bool) -> Address {
+pub fn try_commit(size: usize, executable: bool) -> Option<Address> {
     debug_assert!(mem::is_page_aligned(size));
 
     use kernel32::VirtualAlloc;
@@ -335,10 +348,10 @@ pub fn commit(size: usize, executable: bool) -> Address {
     let ptr = unsafe { VirtualAlloc(ptr::null_mut(), size as u64, MEM_COMMIT | MEM_RESERVE, prot) };
 
     if ptr.is_null() {
-        panic!("VirtualAlloc failed");
+        return None;
     }
 
-    Address::from_ptr(ptr)
+    Some(Address::from_ptr(ptr))
 }
 
 #[cfg(target_family = "unix")]
@@ -522,6 +535,157 @@ impl Access {
     }
 }
 
+/// Makes pages inaccessible while keeping their contents, unlike `protect` with `Access::None`
+/// which discards them.
+#[cfg(target_family = "unix")]
+fn deny_access(start: Address, size: usize) {
+    debug_assert!(start.is_page_aligned());
+    debug_assert!(mem::is_page_aligned(size));
+
+    let res = unsafe { libc::mprotect(start.to_mut_ptr(), size, libc::PROT_NONE) };
+
+    if res != 0 {
+        panic!("mprotect() failed");
+    }
+}
+
+#[cfg(target_family = "windows")]
+fn deny_access(start: Address, size: usize) {
+    debug_assert!(start.is_page_aligned());
+    debug_assert!(mem::is_page_aligned(size));
+
+    use kernel32::VirtualProtect;
+    use winapi::um::winnt::PAGE_NOACCESS;
+
+    let mut old = 0;
+    let res = unsafe { VirtualProtect(start.to_mut_ptr(), size as u64, PAGE_NOACCESS, &mut old) };
+
+    if res == 0 {
+        panic!("VirtualProtect failed");
+    }
+}
+
+/// Evacuated memory protected by `protect_evacuated`.
+struct EvacuatedRegion {
+    start: usize,
+    end: usize,
+    epoch: usize,
+    minor: bool,
+}
+
+lazy_static::lazy_static! {
+    static ref EVACUATED: parking_lot::Mutex<Vec<EvacuatedRegion>> = parking_lot::Mutex::new(vec![]);
+}
+
+/// Makes evacuated memory inaccessible until `unprotect_evacuated` is called. Access to it crashes the process,
+/// on unix fault handler reports the page and the cycle (heap epoch) in which it was evacuated.
+pub fn protect_evacuated(start: Address, size: usize, epoch: usize, minor: bool) {
+    #[cfg(target_family = "unix")]
+    install_fault_handler();
+    EVACUATED.lock().push(EvacuatedRegion {
+        start: start.to_usize(),
+        end: start.to_usize() + size,
+        epoch,
+        minor,
+    });
+    deny_access(start, size);
+}
+
+/// Makes memory protected by `protect_evacuated` accessible again.
+pub fn unprotect_evacuated(start: Address, size: usize) {
+    let mut evacuated = EVACUATED.lock();
+    let len = evacuated.len();
+    evacuated.retain(|region| region.start != start.to_usize());
+    if evacuated.len() != len {
+        protect(start, size, Access::ReadWrite);
+    }
+}
+
+#[cfg(target_family = "unix")]
+static PREVIOUS_HANDLERS: std::sync::OnceLock<[(libc::c_int, libc::sigaction); 2]> =
+    std::sync::OnceLock::new();
+
+#[cfg(target_family = "unix")]
+fn install_fault_handler() {
+    PREVIOUS_HANDLERS.get_or_init(|| unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = fault_handler as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+        libc::sigemptyset(&mut action.sa_mask);
+        let mut previous = [
+            (libc::SIGSEGV, std::mem::zeroed()),
+            (libc::SIGBUS, std::mem::zeroed()),
+        ];
+        for (signal, handler) in previous.iter_mut() {
+            libc::sigaction(*signal, &action, handler);
+        }
+        previous
+    });
+}
+
+/// Reports access to evacuated memory and passes the signal to previously installed handler.
+#[cfg(target_family = "unix")]
+extern "C" fn fault_handler(
+    signal: libc::c_int,
+    info: *mut libc::siginfo_t,
+    context: *mut libc::c_void,
+) {
+    use std::io::Write;
+    let address = unsafe { (*info).si_addr() } as usize;
+    // handler must not block or allocate, message is formatted on the stack.
+    if let Some(evacuated) = EVACUATED.try_lock() {
+        if let Some(region) = evacuated
+            .iter()
+            .find(|region| address >= region.start && address < region.end)
+        {
+            let mut message = [0u8; 256];
+            let mut cursor = std::io::Cursor::new(&mut message[..]);
+            let _ = writeln!(
+                cursor,
+                "cgc: access to {:#x} through stale pointer, page {:#x}-{:#x} was evacuated by {} collection in cycle {}",
+                address,
+                region.start,
+                region.end,
+                if region.minor { "minor" } else { "full" },
+                region.epoch
+            );
+            let len = cursor.position() as usize;
+            unsafe {
+                libc::write(2, message.as_ptr() as *const _, len);
+            }
+        }
+    }
+    let previous = PREVIOUS_HANDLERS
+        .get()
+        .and_then(|handlers| handlers.iter().find(|(handled, _)| *handled == signal))
+        .map(|(_, handler)| *handler);
+    unsafe {
+        match previous {
+            Some(handler)
+                if handler.sa_sigaction != libc::SIG_DFL
+                    && handler.sa_sigaction != libc::SIG_IGN =>
+            {
+                if handler.sa_flags & libc::SA_SIGINFO != 0 {
+                    let handler: extern "C" fn(
+                        libc::c_int,
+                        *mut libc::siginfo_t,
+                        *mut libc::c_void,
+                    ) = std::mem::transmute(handler.sa_sigaction);
+                    handler(signal, info, context);
+                } else {
+                    let handler: extern "C" fn(libc::c_int) =
+                        std::mem::transmute(handler.sa_sigaction);
+                    handler(signal);
+                }
+            }
+            // faulting instruction is executed again and kills the process.
+            _ => {
+                libc::signal(signal, libc::SIG_DFL);
+            }
+        }
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, Hash)]
 pub struct Address(usize);
 
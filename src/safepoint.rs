@@ -1,9 +1,22 @@
-use super::heap::*;
 use super::threads::*;
+use crate::config::{HeapConfig, SafepointTimeoutPolicy};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-pub fn block(thread: &MutatorThread) {
-    let safepoint_id = HEAP.threads.safepoint_id();
-    assert_ne!(safepoint_id, 0);
+use time::{Duration, Instant};
+
+/// Number of heaps that requested safepoint, polled by `safepoint!` so the fast path is a single load.
+pub static SAFEPOINT_POLL: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns true if some heap stops the world, threads should call `gc_guard` then.
+#[inline(always)]
+pub fn safepoint_requested() -> bool {
+    SAFEPOINT_POLL.load(Ordering::Relaxed) != 0
+}
+pub fn block(threads: &Threads, thread: &MutatorThread) {
+    let safepoint_id = threads.safepoint_id();
+    if safepoint_id == 0 {
+        return;
+    }
     let state = thread.state();
 
     match state {
@@ -15,13 +28,13 @@ pub fn block(thread: &MutatorThread) {
         }
     };
 
-    let _mtx = HEAP.threads.barrier.wait(safepoint_id);
+    let _mtx = threads.barrier.wait(safepoint_id);
     thread.unblock();
 }
 
-fn resume_threads(_threads: &[Arc<MutatorThread>], safepoint_id: usize) {
-    HEAP.threads.barrier.resume(safepoint_id);
-    HEAP.threads.clear_safepoint_request();
+fn resume_threads(threads: &Threads, safepoint_id: usize) {
+    threads.barrier.resume(safepoint_id);
+    threads.clear_safepoint_request();
 }
 
 fn all_threads_blocked(
@@ -45,46 +58,269 @@ fn all_threads_blocked(
     all_blocked
 }
 
-fn stop_threads(threads: &[Arc<MutatorThread>]) -> usize {
+/// Reports threads that didn't reach safepoint `safepoint_id` in `elapsed` time according to `policy`.
+fn report_timeout(
+    threads: &Threads,
+    thread_self: &Arc<MutatorThread>,
+    mutators: &[Arc<MutatorThread>],
+    safepoint_id: usize,
+    elapsed: Duration,
+    policy: SafepointTimeoutPolicy,
+) {
+    let running = mutators
+        .iter()
+        .filter(|thread| !Arc::ptr_eq(thread, thread_self) && !thread.in_safepoint(safepoint_id))
+        .map(|thread| thread.describe())
+        .collect::<Vec<_>>();
+    let message = format!(
+        "cgc: threads didn't reach safepoint in {}ms: {}",
+        elapsed.whole_milliseconds(),
+        running.join(", ")
+    );
+    // unwinding collector thread would leave the cycle unfinished, its pauses only log.
+    let policy = match policy {
+        SafepointTimeoutPolicy::Panic if thread_self.heap.borrow().is_none() => {
+            SafepointTimeoutPolicy::Log
+        }
+        policy => policy,
+    };
+    match policy {
+        SafepointTimeoutPolicy::Log => log::warn!("{}", message),
+        SafepointTimeoutPolicy::Panic => {
+            // let blocked threads go, so panic doesn't leave the world stopped.
+            resume_threads(threads, safepoint_id);
+            thread_self.unpark();
+            panic!("{}", message);
+        }
+        SafepointTimeoutPolicy::Abort => {
+            eprintln!("{}", message);
+            std::process::abort();
+        }
+    }
+}
+
+fn stop_threads(threads: &Threads, mutators: &[Arc<MutatorThread>], config: &HeapConfig) -> usize {
     let thread_self = THREAD.with(|thread| thread.borrow().clone());
-    let safepoint_id = HEAP.threads.request_safepoint();
+    let safepoint_id = threads.request_safepoint();
 
-    HEAP.threads.barrier.guard(safepoint_id);
+    threads.barrier.guard(safepoint_id);
 
-    while !all_threads_blocked(&thread_self, threads, safepoint_id) {
+    let start = Instant::now();
+    let mut deadline = config.safepoint_timeout;
+    while !all_threads_blocked(&thread_self, mutators, safepoint_id) {
+        if let Some(timeout) = deadline {
+            let elapsed = start.elapsed();
+            if elapsed >= timeout {
+                report_timeout(
+                    threads,
+                    &thread_self,
+                    mutators,
+                    safepoint_id,
+                    elapsed,
+                    config.safepoint_timeout_policy,
+                );
+                deadline = Some(timeout + config.safepoint_timeout.unwrap());
+            }
+        }
         std::thread::yield_now();
     }
 
     safepoint_id
 }
-pub fn stop_the_world<F, R>(f: F) -> R
+/// Stops all threads attached to `threads` and runs `f` while they're blocked, returns its result and time it took
+/// threads to reach safepoint. Threads that don't reach it in `HeapConfig::safepoint_timeout` are reported.
+pub fn stop_the_world<F, R>(threads: &Threads, config: &HeapConfig, f: F) -> (R, Duration)
 where
     F: FnOnce(&[Arc<MutatorThread>]) -> R,
 {
-    THREAD.with(|thread| thread.borrow().park());
+    let thread_self = THREAD.with(|thread| thread.borrow().clone());
+    thread_self.park();
 
-    let threads = HEAP.threads.threads.lock();
-    if threads.len() == 1 {
-        let ret = f(&*threads);
-        THREAD.with(|thread| thread.borrow().unpark());
-        return ret;
+    let mutators = threads.threads.lock();
+    if mutators
+        .iter()
+        .all(|thread| Arc::ptr_eq(thread, &thread_self))
+    {
+        let ret = f(&*mutators);
+        thread_self.unpark();
+        return (ret, Duration::zero());
     }
 
-    let safepoint_id = stop_threads(&*threads);
-    let ret = f(&*threads);
-    resume_threads(&*threads, safepoint_id);
-    THREAD.with(|thread| thread.borrow().unpark());
-    ret
+    let start = Instant::now();
+    let safepoint_id = stop_threads(threads, &*mutators, config);
+    let time_to_safepoint = start.elapsed();
+    let ret = f(&*mutators);
+    resume_threads(threads, safepoint_id);
+    thread_self.unpark();
+    (ret, time_to_safepoint)
 }
 
+/// Slow path of `safepoint!`, blocks current thread if its heap stops the world.
+#[cold]
 pub extern "C" fn gc_guard() {
     let thread = THREAD.with(|thread| thread.borrow().clone());
-    block(&thread);
+    let heap = thread.heap.borrow().clone();
+    if let Some(heap) = heap {
+        block(&heap.threads, &thread);
+        heap.stress(crate::heap::StressPoint::Safepoint);
+    }
 }
 
+/// Safepoint check, cheap enough to be done on every loop iteration (e.g. backward branch of interpreter).
+/// Blocks current thread while the world is stopped.
 #[macro_export]
 macro_rules! safepoint {
     () => {
-        $crate::safepoint::gc_guard();
+        if $crate::safepoint::safepoint_requested() {
+            $crate::safepoint::gc_guard();
+        }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::heap::testing::*;
+    use crate::threads::mt_alloc;
+    use std::sync::atomic::AtomicBool;
+
+    #[test]
+    fn test_safepoint_poll() {
+        let heap = attached(manual());
+        let stop = Arc::new(AtomicBool::new(false));
+        let (started, wait_started) = std::sync::mpsc::channel();
+        let spinning = {
+            let heap = heap.clone();
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                heap.attach_current_thread();
+                let value = mt_alloc(42usize, false);
+                started.send(()).unwrap();
+                let mut iterations = 0usize;
+                // thread that never allocates reaches safepoints only through the poll.
+                while !stop.load(Ordering::Relaxed) {
+                    crate::safepoint!();
+                    iterations += 1;
+                }
+                assert_eq!(*value, 42);
+                heap.detach_current_thread();
+                iterations
+            })
+        };
+        wait_started.recv().unwrap();
+        for _ in 0..10 {
+            heap.collect_sync();
+        }
+        stop.store(true, Ordering::Relaxed);
+        assert!(spinning.join().unwrap() > 0);
+        finish(&heap);
+    }
+
+    #[test]
+    fn test_safepoint_timeout() {
+        let heap = attached(HeapConfig {
+            safepoint_timeout: Some(Duration::milliseconds(20)),
+            safepoint_timeout_policy: SafepointTimeoutPolicy::Panic,
+            ..manual()
+        });
+        let stop = Arc::new(AtomicBool::new(false));
+        let (started, wait_started) = std::sync::mpsc::channel();
+        let spinning = {
+            let heap = heap.clone();
+            let stop = stop.clone();
+            std::thread::Builder::new()
+                .name("spinner".to_owned())
+                .spawn(move || {
+                    let _guard = crate::thread::MutatorGuard::new(&heap);
+                    started.send(()).unwrap();
+                    // loop without safepoint.
+                    while !stop.load(Ordering::Relaxed) {
+                        std::hint::spin_loop();
+                    }
+                })
+                .unwrap()
+        };
+        wait_started.recv().unwrap();
+        let error =
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| heap.collect())).unwrap_err();
+        let message = error.downcast_ref::<String>().unwrap();
+        assert!(message.contains("'spinner'"), "{}", message);
+        assert!(!message.contains("'safepoint::tests"), "{}", message);
+        // world was resumed, so spinning thread can finish and collection succeeds.
+        stop.store(true, Ordering::Relaxed);
+        spinning.join().unwrap();
+        heap.collect_sync();
+        finish(&heap);
+    }
+
+    #[test]
+    fn test_safepoint_timeout_on_collector() {
+        let heap = attached(HeapConfig {
+            safepoint_timeout: Some(Duration::milliseconds(20)),
+            safepoint_timeout_policy: SafepointTimeoutPolicy::Panic,
+            ..manual()
+        });
+        let spin = Arc::new(AtomicBool::new(false));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (spinning, wait_spinning) = std::sync::mpsc::channel();
+        let spinner = {
+            let heap = heap.clone();
+            let spin = spin.clone();
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                let _guard = crate::thread::MutatorGuard::new(&heap);
+                while !spin.load(Ordering::Relaxed) {
+                    crate::safepoint!();
+                }
+                spinning.send(()).unwrap();
+                // loop without safepoint, so the flip times out.
+                while !stop.load(Ordering::Relaxed) {
+                    std::hint::spin_loop();
+                }
+            })
+        };
+        let wait_spinning = std::sync::Mutex::new(wait_spinning);
+        let callback = heap.add_callback(crate::events::GcEvent::CopyStart, move |_| {
+            spin.store(true, Ordering::Relaxed);
+            wait_spinning.lock().unwrap().recv().unwrap();
+        });
+        let epoch = heap.epoch();
+        heap.collect();
+        crate::enter_native(|| {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            // timeout of the flip is logged, collector keeps waiting instead of panicking.
+            assert!(!heap.collector.lock().as_ref().unwrap().is_finished());
+            stop.store(true, Ordering::Relaxed);
+            spinner.join().unwrap();
+        });
+        heap.wait_for_cycle(epoch);
+        heap.remove_callback(callback);
+        heap.collect_sync();
+        assert_eq!(heap.stats().cycles, 2);
+        finish(&heap);
+    }
+
+    #[test]
+    fn test_time_to_safepoint() {
+        let heap = attached(manual());
+        let (started, wait_started) = std::sync::mpsc::channel();
+        let late = {
+            let heap = heap.clone();
+            std::thread::spawn(move || {
+                let _guard = crate::thread::MutatorGuard::new(&heap);
+                started.send(()).unwrap();
+                std::thread::sleep(std::time::Duration::from_millis(20));
+                crate::safepoint!();
+            })
+        };
+        wait_started.recv().unwrap();
+        heap.collect_sync();
+        late.join().unwrap();
+        let stats = heap.stats();
+        let cycle = stats.history.back().unwrap();
+        assert_eq!(cycle.pauses.len(), cycle.times_to_safepoint.len());
+        assert!(stats.max_time_to_safepoint >= Duration::milliseconds(10));
+        assert!(stats.max_time_to_safepoint <= stats.max_pause);
+        finish(&heap);
+    }
+}
@@ -1,4 +1,27 @@
 use crate::mem::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Byte evacuated memory is filled with in stress mode.
+pub const POISON: u8 = 0xdb;
+
+/// Commits new page unless `committed` would go over `max_committed` bytes.
+fn try_commit_page(committed: &AtomicUsize, size: usize, max_committed: usize) -> Option<Page> {
+    if committed
+        .fetch_add(size, Ordering::AcqRel)
+        .saturating_add(size)
+        > max_committed
+    {
+        committed.fetch_sub(size, Ordering::AcqRel);
+        return None;
+    }
+    let page = Page::try_new(size);
+    if page.is_none() {
+        committed.fetch_sub(size, Ordering::AcqRel);
+    }
+    page
+}
+
 pub struct Space {
     pub top: Address,
     pub limit: Address,
@@ -8,6 +31,8 @@ pub struct Space {
     pub page_size: usize,
     pub pages_count: usize,
     pub allocated_size: usize,
+    /// Amount of committed bytes, shared by all spaces of heap.
+    pub committed: Arc<AtomicUsize>,
 }
 
 impl Space {
@@ -21,6 +46,7 @@ impl Space {
             page_size: 0,
             pages_count: 0,
             size_limit: 0,
+            committed: Arc::new(AtomicUsize::new(0)),
         }
     }
     pub fn new(page_size: usize) -> Self {
@@ -33,18 +59,23 @@ impl Space {
             top,
             limit,
             pages,
-            size: 0,
+            size: page_size,
             page_size,
             size_limit: 0,
             pages_count: 1,
             allocated_size: 0,
+            committed: Arc::new(AtomicUsize::new(page_size)),
         };
         space.compute_size_limit();
         space
     }
 
+    /// Recomputes the amount of allocated bytes after which this space asks for a collection.
+    ///
+    /// The limit is twice the bytes currently allocated (i.e. the survivors of the last cycle)
+    /// but never less than two pages.
     pub fn compute_size_limit(&mut self) {
-        self.size_limit = self.size << 1;
+        self.size_limit = std::cmp::max(self.allocated_size, self.page_size) << 1;
     }
     pub fn may_allocate_in_current(&mut self, size: usize) -> bool {
         let even_bytes = size + (size & 0x01);
@@ -52,14 +83,25 @@ impl Space {
         place_in_current
     }
     pub fn add_page(&mut self, size: usize) {
+        assert!(
+            self.try_add_page(size, usize::MAX),
+            "committing memory failed"
+        );
+    }
+    /// Adds new page, returns false if heap would go over `max_committed` bytes or memory can't be committed.
+    pub fn try_add_page(&mut self, size: usize, max_committed: usize) -> bool {
         let real_size = align_usize(size, page_size());
-        let page = Page::new(real_size);
+        let page = match try_commit_page(&self.committed, real_size, max_committed) {
+            Some(page) => page,
+            None => return false,
+        };
         self.pages.push(page);
         self.pages_count += 1;
         let page = self.pages.last().unwrap();
         self.size += real_size;
         self.top = Address::from_ptr(&page.top);
         self.limit = Address::from_ptr(&page.limit);
+        true
     }
 
     pub fn fast_allocate(&mut self, bytes: usize, needs_gc: &mut bool) -> Address {
@@ -89,27 +131,45 @@ impl Space {
         return None;
     }
     pub fn allocate(&mut self, bytes: usize, needs_gc: &mut bool) -> Address {
+        self.try_allocate(bytes, needs_gc, usize::MAX)
+            .expect("committing memory failed")
+    }
+
+    /// Allocates `bytes`, returns `None` if new page is needed and committed memory of heap would go over
+    /// `max_committed` bytes or memory can't be committed.
+    pub fn try_allocate(
+        &mut self,
+        bytes: usize,
+        needs_gc: &mut bool,
+        max_committed: usize,
+    ) -> Option<Address> {
         let even_bytes = bytes + (bytes & 0x01);
-        let place_in_current = self.top.deref().offset(even_bytes) < self.limit.deref();
+        let place_in_current = self.top.deref().offset(even_bytes) <= self.limit.deref();
 
         if !place_in_current {
-            let head = self.try_find_page_for(even_bytes);
-
-            if let None = head {
-                *needs_gc = true;
-                self.add_page(even_bytes);
-            } else if let Some((top, limit)) = head {
-                self.top = top;
-                self.limit = limit;
+            match self.try_find_page_for(even_bytes) {
+                Some((top, limit)) => {
+                    self.top = top;
+                    self.limit = limit;
+                }
+                None => {
+                    if !self.try_add_page(std::cmp::max(even_bytes, self.page_size), max_committed)
+                    {
+                        return None;
+                    }
+                }
             }
         }
         self.allocated_size += even_bytes;
+        if self.allocated_size >= self.size_limit {
+            *needs_gc = true;
+        }
         let result = self.top.deref();
         unsafe {
             *self.top.to_mut_ptr::<*mut u8>() =
                 self.top.deref().offset(even_bytes).to_mut_ptr::<u8>();
         }
-        result
+        Some(result)
     }
 
     pub fn swap(&mut self, space: &mut Space) {
@@ -119,16 +179,61 @@ impl Space {
             self.pages.push(space.pages.pop().unwrap());
             self.size += self.pages.last().unwrap().size;
         }
+        space.size = 0;
         self.allocated_size = space.allocated_size;
         let page = self.pages.last().unwrap();
         self.top = Address::from_ptr(&page.top);
         self.limit = Address::from_ptr(&page.limit);
     }
 
+    /// Removes pages for which `f` returns true, they stay committed.
+    pub fn take_pages(&mut self, mut f: impl FnMut(&Page) -> bool) -> Vec<Page> {
+        // `top` and `limit` point into `pages`, it's rebuilt only if some page is taken.
+        if !self.pages.iter().any(&mut f) {
+            return Vec::new();
+        }
+        let (taken, pages): (Vec<Page>, Vec<Page>) = std::mem::take(&mut self.pages)
+            .into_iter()
+            .partition(|page| f(page));
+        self.pages = pages;
+        for page in taken.iter() {
+            self.size -= page.size;
+            self.pages_count -= 1;
+        }
+        if self.pages.is_empty() {
+            self.add_page(self.page_size);
+        }
+        let page = self.pages.last().unwrap();
+        self.top = Address::from_ptr(&page.top);
+        self.limit = Address::from_ptr(&page.limit);
+        taken
+    }
+
+    /// Adds pages taken from another space with `take_pages`, allocation continues in the current page.
+    pub fn adopt_pages(&mut self, pages: Vec<Page>) {
+        if pages.is_empty() {
+            return;
+        }
+        let current = self
+            .pages
+            .iter()
+            .position(|page| Address::from_ptr(&page.top) == self.top);
+        for page in pages {
+            self.size += page.size;
+            self.allocated_size += page.top.offset_from(page.data);
+            self.pages_count += 1;
+            self.pages.push(page);
+        }
+        // pages might have been moved.
+        let page = &self.pages[current.unwrap_or(self.pages.len() - 1)];
+        self.top = Address::from_ptr(&page.top);
+        self.limit = Address::from_ptr(&page.limit);
+    }
+
     pub fn contains(&self, addr: Address) -> bool {
         for page in self.pages.iter() {
             let page: &Page = page;
-            if addr >= page.data && addr <= page.limit {
+            if addr >= page.data && addr < page.limit {
                 return true;
             }
         }
@@ -136,12 +241,41 @@ impl Space {
         false
     }
 
+    /// Fills free memory of all pages with `POISON`.
+    pub fn poison(&self) {
+        for page in self.pages.iter() {
+            unsafe {
+                std::ptr::write_bytes(
+                    page.top.to_mut_ptr::<u8>(),
+                    POISON,
+                    page.limit.offset_from(page.top),
+                );
+            }
+        }
+    }
+
+    /// Protects all pages until `unprotect` is called, space must be reset.
+    pub fn protect(&self, epoch: usize, minor: bool) {
+        for page in self.pages.iter() {
+            protect_evacuated(page.data, page.size, epoch, minor);
+        }
+    }
+
+    pub fn unprotect(&self) {
+        for page in self.pages.iter() {
+            unprotect_evacuated(page.data, page.size);
+        }
+    }
+
     pub fn reset_pages(&mut self) {
         for page in self.pages.iter_mut() {
             page.top = page.data;
         }
+        self.allocated_size = 0;
     }
     pub fn clear(&mut self) {
+        self.unprotect();
+        self.committed.fetch_sub(self.size, Ordering::AcqRel);
         self.size = 0;
         while let Some(page) = self.pages.pop() {
             page.uncommit();
@@ -149,6 +283,163 @@ impl Space {
     }
 }
 
+/// Chunk of memory carved from a space, allows bump allocation without locking the space.
+pub struct LocalAllocationBuffer {
+    pub start: Address,
+    pub top: Address,
+    pub limit: Address,
+}
+
+impl LocalAllocationBuffer {
+    pub fn empty() -> Self {
+        Self {
+            start: Address::null(),
+            top: Address::null(),
+            limit: Address::null(),
+        }
+    }
+
+    /// Bump allocates `bytes` from buffer, returns `None` if there's no room left.
+    pub fn allocate(&mut self, bytes: usize) -> Option<Address> {
+        let even_bytes = bytes + (bytes & 0x01);
+        if self.top.is_null() || self.top.offset(even_bytes) > self.limit {
+            return None;
+        }
+        let result = self.top;
+        self.top = self.top.offset(even_bytes);
+        Some(result)
+    }
+
+    /// Retires current buffer and carves new one of `size` bytes from `space`, returns amount of unused bytes in retired buffer.
+    pub fn refill(&mut self, space: &mut Space, size: usize, needs_gc: &mut bool) -> usize {
+        let retired = self.retire(space);
+        assert!(
+            self.try_fill(space, size, needs_gc, usize::MAX),
+            "committing memory failed"
+        );
+        retired
+    }
+
+    /// Carves new buffer from `space`, current buffer must be retired first. Returns false if `space` can't
+    /// allocate `size` bytes without going over `max_committed`.
+    pub fn try_fill(
+        &mut self,
+        space: &mut Space,
+        size: usize,
+        needs_gc: &mut bool,
+        max_committed: usize,
+    ) -> bool {
+        debug_assert!(self.top.is_null());
+        match space.try_allocate(size, needs_gc, max_committed) {
+            Some(top) => {
+                self.start = top;
+                self.top = top;
+                self.limit = top.offset(size + (size & 0x01));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns amount of bytes allocated from current buffer.
+    pub fn used(&self) -> usize {
+        self.top.offset_from(self.start)
+    }
+
+    /// Gives unused memory back to `space` and returns its size. Buffer must be retired before `space` is reset.
+    pub fn retire(&mut self, space: &mut Space) -> usize {
+        if self.top.is_null() {
+            return 0;
+        }
+        let unused = self.limit.offset_from(self.top);
+        space.allocated_size -= unused;
+        // buffer is at the end of current page, so unused memory can be reused.
+        if space.top.deref() == self.limit {
+            unsafe {
+                *space.top.to_mut_ptr::<Address>() = self.top;
+            }
+        }
+        *self = Self::empty();
+        unused
+    }
+}
+
+/// Space for large objects, each object gets its own pages, it is never copied and its pages are released once it dies.
+pub struct LargeObjectSpace {
+    pub objects: Vec<Page>,
+    pub allocated_size: usize,
+    pub size_limit: usize,
+    pub committed: Arc<AtomicUsize>,
+}
+
+impl LargeObjectSpace {
+    pub fn empty() -> Self {
+        Self {
+            objects: Vec::new(),
+            allocated_size: 0,
+            size_limit: 0,
+            committed: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn allocate(&mut self, bytes: usize, needs_gc: &mut bool) -> Address {
+        self.try_allocate(bytes, needs_gc, usize::MAX)
+            .expect("committing memory failed")
+    }
+
+    /// Allocates `bytes`, returns `None` if committed memory of heap would go over `max_committed` bytes.
+    pub fn try_allocate(
+        &mut self,
+        bytes: usize,
+        needs_gc: &mut bool,
+        max_committed: usize,
+    ) -> Option<Address> {
+        let page = try_commit_page(
+            &self.committed,
+            align_usize(bytes, page_size()),
+            max_committed,
+        )?;
+        self.allocated_size += page.size;
+        if self.allocated_size >= self.size_limit {
+            *needs_gc = true;
+        }
+        let result = page.data;
+        self.objects.push(page);
+        Some(result)
+    }
+
+    /// Uncommits pages of objects for which `is_dead` returns true.
+    pub fn sweep(&mut self, mut is_dead: impl FnMut(Address) -> bool) {
+        let mut allocated_size = 0;
+        let committed = &self.committed;
+        self.objects.retain(|page| {
+            if is_dead(page.data) {
+                page.uncommit();
+                committed.fetch_sub(page.size, Ordering::AcqRel);
+                false
+            } else {
+                allocated_size += page.size;
+                true
+            }
+        });
+        self.allocated_size = allocated_size;
+    }
+
+    pub fn contains(&self, addr: Address) -> bool {
+        self.objects
+            .iter()
+            .any(|page| addr >= page.data && addr < page.limit)
+    }
+
+    pub fn clear(&mut self) {
+        for page in self.objects.drain(..) {
+            page.uncommit();
+            self.committed.fetch_sub(page.size, Ordering::AcqRel);
+        }
+        self.allocated_size = 0;
+    }
+}
+
 pub struct Page {
     pub data: Address,
     pub top: Address,
@@ -158,15 +449,19 @@ pub struct Page {
 
 impl Page {
     pub fn new(size: usize) -> Self {
-        let data = commit(size, false);
+        Self::try_new(size).expect("committing memory failed")
+    }
+
+    pub fn try_new(size: usize) -> Option<Self> {
+        let data = try_commit(size, false)?;
         let top = data;
         let limit = data.offset(size);
-        Self {
+        Some(Self {
             top,
             data,
             limit,
             size,
-        }
+        })
     }
 
     pub fn uncommit(&self) {
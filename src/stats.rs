@@ -0,0 +1,203 @@
+use crate::space::*;
+use std::collections::VecDeque;
+use time::Duration;
+
+/// Summary of one collection cycle.
+#[derive(Clone, Debug, Default)]
+pub struct CycleStats {
+    /// Heap epoch after this cycle was finished.
+    pub epoch: usize,
+    /// Cycle collected only young generation.
+    pub minor: bool,
+    /// Bytes allocated by mutators since previous cycle.
+    pub bytes_allocated: usize,
+    pub bytes_copied: usize,
+    pub surviving_objects: usize,
+    /// Durations of stop-the-world pauses of this cycle.
+    pub pauses: Vec<Duration>,
+    /// Time it took mutators to reach safepoint, for each pause in `pauses`.
+    pub times_to_safepoint: Vec<Duration>,
+    /// Time spent copying objects while mutators were running.
+    pub copy_time: Duration,
+}
+
+/// Stop-the-world pause.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Pause {
+    /// Whole pause including waiting for mutators.
+    pub duration: Duration,
+    pub time_to_safepoint: Duration,
+}
+
+impl CycleStats {
+    pub(crate) fn add_pause(&mut self, pause: Pause) {
+        self.pauses.push(pause.duration);
+        self.times_to_safepoint.push(pause.time_to_safepoint);
+    }
+
+    pub fn pause_time(&self) -> Duration {
+        self.pauses
+            .iter()
+            .fold(Duration::zero(), |total, pause| total + *pause)
+    }
+}
+
+/// Memory used by one space.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SpaceStats {
+    pub pages: usize,
+    pub committed_bytes: usize,
+    pub allocated_bytes: usize,
+}
+
+impl SpaceStats {
+    pub(crate) fn of(space: &Space) -> Self {
+        Self {
+            pages: space.pages.len(),
+            committed_bytes: space.size,
+            allocated_bytes: space.allocated_size,
+        }
+    }
+
+    pub(crate) fn of_large(space: &LargeObjectSpace) -> Self {
+        Self {
+            pages: space.objects.len(),
+            committed_bytes: space.objects.iter().map(|page| page.size).sum(),
+            allocated_bytes: space.allocated_size,
+        }
+    }
+}
+
+/// Heap statistics returned by `GlobalHeap::stats`.
+#[derive(Clone, Debug, Default)]
+pub struct HeapStats {
+    /// Number of finished full collection cycles.
+    pub cycles: usize,
+    pub minor_cycles: usize,
+    /// Bytes allocated by mutators since heap was created, objects in allocation buffers are counted
+    /// once buffers are retired.
+    pub bytes_allocated: usize,
+    pub bytes_copied: usize,
+    /// Number of objects that survived last cycle.
+    pub surviving_objects: usize,
+    pub pause_time: Duration,
+    pub max_pause: Duration,
+    /// Longest time mutators took to reach safepoint.
+    pub max_time_to_safepoint: Duration,
+    pub copy_time: Duration,
+    /// Memory committed by all spaces.
+    pub committed_bytes: usize,
+    pub from_space: SpaceStats,
+    pub to_space: SpaceStats,
+    pub nursery: SpaceStats,
+    pub survivor: SpaceStats,
+    pub large_space: SpaceStats,
+    /// Most recent cycles, oldest first. Length is limited by `HeapConfig::stats_history`.
+    pub history: VecDeque<CycleStats>,
+}
+
+impl HeapStats {
+    pub(crate) fn record(&mut self, cycle: CycleStats, history: usize) {
+        if cycle.minor {
+            self.minor_cycles += 1;
+        } else {
+            self.cycles += 1;
+        }
+        self.bytes_copied += cycle.bytes_copied;
+        self.surviving_objects = cycle.surviving_objects;
+        for pause in cycle.pauses.iter() {
+            self.pause_time += *pause;
+            if *pause > self.max_pause {
+                self.max_pause = *pause;
+            }
+        }
+        for time in cycle.times_to_safepoint.iter() {
+            if *time > self.max_time_to_safepoint {
+                self.max_time_to_safepoint = *time;
+            }
+        }
+        self.copy_time += cycle.copy_time;
+        self.history.push_back(cycle);
+        while self.history.len() > history {
+            self.history.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::HeapConfig;
+    use crate::heap::testing::*;
+    use crate::heap::HeapInner;
+    use crate::threads::mt_alloc;
+
+    #[test]
+    fn test_stats() {
+        let heap = attached(HeapConfig {
+            stats_history: 2,
+            ..manual()
+        });
+        let live = (0..100).map(|i| mt_alloc(i, false)).collect::<Vec<_>>();
+        for i in 0..100 {
+            mt_alloc(i, false);
+        }
+        let size = std::mem::size_of::<HeapInner<i32>>();
+        heap.collect_sync();
+        let stats = heap.stats();
+        assert_eq!(stats.cycles, 1);
+        assert_eq!(stats.bytes_allocated, 200 * size);
+        assert_eq!(stats.bytes_copied, 100 * size);
+        assert_eq!(stats.surviving_objects, 100);
+        assert_eq!(stats.from_space.allocated_bytes, 100 * size);
+        assert!(stats.from_space.pages > 0);
+        let cycle = &stats.history[0];
+        assert_eq!(cycle.epoch, 1);
+        assert_eq!(cycle.bytes_allocated, 200 * size);
+        assert_eq!(cycle.pauses.len(), 2);
+        assert_eq!(cycle.pause_time(), stats.pause_time);
+
+        heap.collect_sync();
+        heap.collect_sync();
+        let stats = heap.stats();
+        assert_eq!(stats.cycles, 3);
+        assert_eq!(stats.history.len(), 2);
+        assert_eq!(stats.history[1].epoch, 3);
+        assert_eq!(stats.history[1].bytes_allocated, 0);
+        drop(live);
+        finish(&heap);
+    }
+
+    #[test]
+    fn test_stats_with_concurrent_allocation() {
+        let heap = attached(HeapConfig {
+            // every cycle is kept, so per-cycle amounts can be added up.
+            stats_history: usize::MAX,
+            ..generational(2)
+        });
+        let mutators = (0..3)
+            .map(|_| {
+                crate::thread::spawn(move || {
+                    for i in 0..100_000usize {
+                        mt_alloc(i, false);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        // minor collections are started by mutators while others are allocating.
+        crate::enter_native(|| {
+            for mutator in mutators {
+                mutator.join().unwrap();
+            }
+        });
+        heap.collect_sync();
+        let allocated = 3 * 100_000 * std::mem::size_of::<HeapInner<usize>>();
+        let stats = heap.stats();
+        assert!(stats.minor_cycles > 0);
+        assert_eq!(stats.bytes_allocated, allocated);
+        assert_eq!(stats.history.len(), stats.cycles + stats.minor_cycles);
+        // memory allocated from TLABs of running threads is counted by cycle that retires them.
+        let cycles = stats.history.iter().map(|cycle| cycle.bytes_allocated);
+        assert_eq!(cycles.sum::<usize>(), allocated);
+        finish(&heap);
+    }
+}
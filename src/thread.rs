@@ -0,0 +1,82 @@
+//! Threads attached to GC heap for their whole lifetime.
+use crate::heap::GlobalHeap;
+use crate::threads::THREAD;
+use std::sync::Arc;
+
+/// Attaches current thread to heap until the guard is dropped, including drop while the thread unwinds after
+/// panic, so exited thread never blocks stop-the-world. `Rooted`s that outlive the guard keep their objects alive.
+pub struct MutatorGuard {
+    heap: Arc<GlobalHeap>,
+    /// Guard belongs to the thread it attached.
+    marker: std::marker::PhantomData<*const ()>,
+}
+
+impl MutatorGuard {
+    pub fn new(heap: &Arc<GlobalHeap>) -> Self {
+        heap.attach_current_thread();
+        Self {
+            heap: heap.clone(),
+            marker: std::marker::PhantomData,
+        }
+    }
+}
+
+impl Drop for MutatorGuard {
+    fn drop(&mut self) {
+        self.heap.detach_current_thread();
+    }
+}
+
+/// Spawns thread attached to heap the current thread is attached to, or to the default heap if it's not attached.
+/// Thread is detached once `f` returns or panics.
+pub fn spawn<F, T>(f: F) -> std::thread::JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let heap = THREAD
+        .with(|thread| thread.borrow().heap.borrow().clone())
+        .unwrap_or_else(|| crate::heap::HEAP.clone());
+    std::thread::spawn(move || {
+        let _guard = MutatorGuard::new(&heap);
+        f()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::heap::testing::*;
+    use crate::threads::mt_alloc;
+
+    #[test]
+    fn test_spawn() {
+        let heap = attached(manual());
+        let sum = spawn(|| {
+            let values = (0..100).map(|i| mt_alloc(i, false)).collect::<Vec<_>>();
+            values.iter().map(|value| **value).sum::<usize>()
+        });
+        assert_eq!(sum.join().unwrap(), 4950);
+        let panicked = spawn(|| {
+            let _value = mt_alloc(1usize, false);
+            panic!("mutator panicked");
+        });
+        assert!(panicked.join().is_err());
+        // exited threads are detached, so they don't block stop-the-world.
+        assert_eq!(heap.threads.threads.lock().len(), 1);
+        heap.collect_sync();
+
+        heap.detach_current_thread();
+        let guard = MutatorGuard::new(&heap);
+        let value = mt_alloc(1usize, false);
+        drop(value);
+        assert_eq!(
+            THREAD.with(|thread| thread.borrow().rootset.borrow().len()),
+            1
+        );
+        drop(guard);
+        assert!(heap.threads.threads.lock().is_empty());
+        assert!(THREAD.with(|thread| thread.borrow().rootset.borrow().is_empty()));
+        heap.shutdown();
+    }
+}
@@ -1,3 +1,4 @@
+use crate::heap::{AllocError, GlobalHeap};
 use parking_lot::{Condvar, Mutex};
 use std::sync::{atomic::AtomicUsize, atomic::Ordering, Arc};
 pub struct Barrier {
@@ -66,6 +67,18 @@ impl StateManager {
         mtx.0 = ThreadState::Running;
     }
 
+    /// Unparks thread unless safepoint is requested, returns id of requested safepoint or 0 if thread was unparked.
+    /// Safepoint is checked while state is locked, so thread that collector saw as parked stays parked.
+    fn unpark_unless_requested(&self, threads: &Threads) -> usize {
+        let mut mtx = self.mtx.lock();
+        assert!(mtx.0.is_parked());
+        let safepoint_id = threads.safepoint_id();
+        if safepoint_id == 0 {
+            mtx.0 = ThreadState::Running;
+        }
+        safepoint_id
+    }
+
     fn block(&self, safepoint_id: usize) {
         let mut mtx = self.mtx.lock();
         assert!(mtx.0.is_running());
@@ -146,6 +159,12 @@ impl Default for ThreadState {
 pub struct MutatorThread {
     pub state: StateManager,
     pub rootset: std::cell::RefCell<Vec<*mut dyn super::api::RootedTrait>>,
+    /// Heap this thread is attached to.
+    pub(crate) heap: std::cell::RefCell<Option<Arc<GlobalHeap>>>,
+    /// Thread local allocation buffer, retired by collector when allocation space changes.
+    pub(crate) tlab: std::cell::RefCell<crate::space::LocalAllocationBuffer>,
+    /// Thread this mutator was created on, used in diagnostics.
+    pub(crate) thread: std::thread::Thread,
 }
 
 impl MutatorThread {
@@ -153,19 +172,47 @@ impl MutatorThread {
         Self {
             state: StateManager::new(),
             rootset: std::cell::RefCell::new(vec![]),
+            heap: std::cell::RefCell::new(None),
+            tlab: std::cell::RefCell::new(crate::space::LocalAllocationBuffer::empty()),
+            thread: std::thread::current(),
         }
     }
+
+    /// Name and id of the thread, e.g. `'worker' (ThreadId(3))`.
+    pub fn describe(&self) -> String {
+        format!(
+            "'{}' ({:?})",
+            self.thread.name().unwrap_or("<unnamed>"),
+            self.thread.id()
+        )
+    }
     pub fn state(&self) -> ThreadState {
         self.state.state()
     }
 
+    /// Runs `f` with heap this thread is attached to.
+    ///
+    /// # Panics
+    /// Panics if thread is not attached to any heap.
+    pub fn with_heap<R>(&self, f: impl FnOnce(&Arc<GlobalHeap>) -> R) -> R {
+        let heap = self.heap.borrow();
+        f(heap.as_ref().expect("thread is not attached to GC heap"))
+    }
+
     pub fn park(&self) {
         self.state.park();
     }
 
     pub fn unpark(&self) {
-        if super::heap::HEAP.threads.safepoint_id() != 0 {
-            crate::safepoint::block(self);
+        if let Some(heap) = &*self.heap.borrow() {
+            // parked thread counts as stopped, it must not run until the world is resumed.
+            loop {
+                let safepoint_id = self.state.unpark_unless_requested(&heap.threads);
+                if safepoint_id == 0 {
+                    return;
+                }
+                heap.threads.barrier.wait(safepoint_id);
+            }
         }
 
         self.state.unpark();
@@ -191,7 +238,9 @@ pub struct Threads {
     pub cond_join: Condvar,
 
     pub next_id: AtomicUsize,
-    pub safepoint: Mutex<(usize, usize)>,
+    /// Id of requested safepoint, 0 if there is none.
+    pub safepoint: AtomicUsize,
+    pub next_safepoint: AtomicUsize,
 
     pub barrier: Barrier,
 }
@@ -202,7 +251,8 @@ impl Threads {
             threads: Mutex::new(Vec::new()),
             cond_join: Condvar::new(),
             next_id: AtomicUsize::new(1),
-            safepoint: Mutex::new((0, 1)),
+            safepoint: AtomicUsize::new(0),
+            next_safepoint: AtomicUsize::new(1),
             barrier: Barrier::new(),
         }
     }
@@ -224,28 +274,26 @@ impl Threads {
     }
 
     pub fn safepoint_id(&self) -> usize {
-        let safepoint = self.safepoint.lock();
-        safepoint.0
+        self.safepoint.load(Ordering::SeqCst)
     }
 
     pub fn safepoint_requested(&self) -> bool {
-        let safepoint = self.safepoint.lock();
-        safepoint.0 != 0
+        self.safepoint_id() != 0
     }
 
     pub fn request_safepoint(&self) -> usize {
-        let mut safepoint = self.safepoint.lock();
-        assert_eq!(safepoint.0, 0);
-        safepoint.0 = safepoint.1;
-        safepoint.1 += 1;
+        let safepoint_id = self.next_safepoint.fetch_add(1, Ordering::Relaxed);
+        let previous = self.safepoint.swap(safepoint_id, Ordering::SeqCst);
+        assert_eq!(previous, 0);
+        crate::safepoint::SAFEPOINT_POLL.fetch_add(1, Ordering::SeqCst);
 
-        safepoint.0
+        safepoint_id
     }
 
     pub fn clear_safepoint_request(&self) {
-        let mut safepoint = self.safepoint.lock();
-        assert_ne!(safepoint.0, 0);
-        safepoint.0 = 0;
+        let previous = self.safepoint.swap(0, Ordering::SeqCst);
+        assert_ne!(previous, 0);
+        crate::safepoint::SAFEPOINT_POLL.fetch_sub(1, Ordering::SeqCst);
     }
 
     pub fn detach_current_thread(&self) {
@@ -281,34 +329,126 @@ thread_local! {
     pub static THREAD: std::cell::RefCell<Arc<MutatorThread>> = std::cell::RefCell::new(Arc::new(MutatorThread::new()));
 }
 
+/// Attaches current thread to the default heap.
 pub extern "C" fn attach_current_thread() {
-    crate::heap::HEAP.threads.attach_current_thread();
+    crate::heap::HEAP.attach_current_thread();
 }
 
+/// Detaches current thread from heap it is attached to.
 pub extern "C" fn detach_current_thread() {
-    crate::heap::HEAP.threads.attach_current_thread();
+    let heap = THREAD.with(|thread| thread.borrow().with_heap(|heap| heap.clone()));
+    heap.detach_current_thread();
+}
+/// Guard of native region: while it is alive current thread is parked, collector treats it as being at safepoint
+/// and stops the world without waiting for it. Dropping the guard unparks the thread, if the world is stopped at
+/// that moment thread waits until it's resumed.
+///
+/// Thread must not access GC objects, allocate or collect while it is in native region and regions can't be nested.
+pub struct NativeRegion {
+    thread: Arc<MutatorThread>,
+    /// Guard belongs to the thread that entered the region.
+    marker: std::marker::PhantomData<*const ()>,
+}
+
+impl NativeRegion {
+    pub fn enter() -> Self {
+        let thread = THREAD.with(|thread| thread.borrow().clone());
+        thread.park();
+        Self {
+            thread,
+            marker: std::marker::PhantomData,
+        }
+    }
 }
+
+impl Drop for NativeRegion {
+    fn drop(&mut self) {
+        self.thread.unpark();
+    }
+}
+
 use crate::api::*;
-/// Use this function to allocate object in GC heap.
+/// Use this function to allocate object in GC heap of the current thread.
 ///
 /// If value needs finalization `finalize` argument should be true.
+///
+/// Allocation is a safepoint and might start a collection cycle once heap grows over its threshold.
 pub fn mt_alloc<T: Trace + Sized + 'static>(value: T, finalize: bool) -> Rooted<T> {
-    let mem = crate::heap::HEAP.allocate(value, finalize);
-    let rooted = Box::into_raw(Box::new(RootedInner {
-        rooted: true,
-        inner: mem,
-    }));
-
-    THREAD.with(|th| th.borrow().rootset.borrow_mut().push(rooted));
+    match try_mt_alloc(value, finalize) {
+        Ok(rooted) => rooted,
+        Err(error) => panic!("{}", error),
+    }
+}
 
-    Rooted { inner: rooted }
+/// Like `mt_alloc` but returns `AllocError` instead of panicking if heap is out of memory even after emergency collection.
+pub fn try_mt_alloc<T: Trace + Sized + 'static>(
+    value: T,
+    finalize: bool,
+) -> Result<Rooted<T>, AllocError> {
+    THREAD.with(|th| {
+        let th = th.borrow();
+        th.with_heap(|heap| {
+            let mem = heap.try_allocate(value, finalize)?;
+            let rooted = Box::into_raw(Box::new(RootedInner {
+                rooted: std::sync::atomic::AtomicBool::new(true),
+                inner: std::cell::Cell::new(mem),
+            }));
+            th.rootset.borrow_mut().push(rooted);
+            crate::safepoint::block(&heap.threads, &th);
+            heap.collect_if_needed();
+            heap.stress(crate::heap::StressPoint::Allocation);
+
+            Ok(Rooted { inner: rooted })
+        })
+    })
 }
 
 pub fn mt_root<T: Trace + 'static + Sized>(handle: Handle<T>) -> Rooted<T> {
     let rooted = Box::into_raw(Box::new(RootedInner {
-        rooted: true,
-        inner: handle.inner,
+        rooted: std::sync::atomic::AtomicBool::new(true),
+        inner: std::cell::Cell::new(handle.inner),
     }));
-    THREAD.with(|th| th.borrow().rootset.borrow_mut().push(rooted));
+    THREAD.with(|th| {
+        let th = th.borrow();
+        th.rootset.borrow_mut().push(rooted);
+        th.with_heap(|heap| {
+            heap.shade(handle.inner);
+            heap.stress(crate::heap::StressPoint::Root);
+        });
+    });
     Rooted { inner: rooted }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::heap::testing::*;
+
+    #[test]
+    fn test_native_region() {
+        let heap = attached(manual());
+        let (started, wait_started) = std::sync::mpsc::channel();
+        let (wake, wait_wake) = std::sync::mpsc::channel::<()>();
+        let blocked = {
+            let heap = heap.clone();
+            std::thread::spawn(move || {
+                heap.attach_current_thread();
+                let value = mt_alloc(42usize, false);
+                let address = address_of(&value);
+                crate::enter_native(|| {
+                    started.send(()).unwrap();
+                    // thread blocked in native code doesn't stop collections.
+                    wait_wake.recv().unwrap();
+                });
+                assert_ne!(address_of(&value), address);
+                assert_eq!(*value, 42);
+                heap.detach_current_thread();
+            })
+        };
+        wait_started.recv().unwrap();
+        heap.collect_sync();
+        wake.send(()).unwrap();
+        blocked.join().unwrap();
+        finish(&heap);
+    }
+}
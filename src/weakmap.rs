@@ -0,0 +1,179 @@
+use crate::api::*;
+use crate::heap::*;
+use crate::mem::Address;
+use fxhash::FxHashMap;
+use std::sync::Arc;
+
+/// Entries of `GcWeakMap`, keyed by address of key object. Collector removes entries of dead keys and rehashes
+/// entries when keys are moved.
+pub(crate) struct EphemeronTable {
+    pub(crate) entries: parking_lot::Mutex<FxHashMap<usize, *mut HeapInner<dyn Trace>>>,
+}
+
+unsafe impl Send for EphemeronTable {}
+unsafe impl Sync for EphemeronTable {}
+
+/// Ephemeron table: maps GC objects to GC values, value is kept alive only while its key is reachable from
+/// elsewhere and entry is removed once key dies.
+///
+/// Map is not traced, when it is stored in GC object that object must be allocated with `finalize` set to true.
+pub struct GcWeakMap<K: Trace, V: Trace> {
+    table: Arc<EphemeronTable>,
+    heap: Arc<GlobalHeap>,
+    marker: std::marker::PhantomData<(K, V)>,
+}
+
+impl<K: Trace + 'static, V: Trace + 'static> GcWeakMap<K, V> {
+    /// Creates empty map in heap the current thread is attached to.
+    pub fn new() -> Self {
+        let heap =
+            crate::threads::THREAD.with(|thread| thread.borrow().with_heap(|heap| heap.clone()));
+        let table = Arc::new(EphemeronTable {
+            entries: parking_lot::Mutex::new(FxHashMap::default()),
+        });
+        heap.register_ephemerons(table.clone());
+        Self {
+            table,
+            heap,
+            marker: std::marker::PhantomData,
+        }
+    }
+
+    /// Returns key under which entry for `key` object is stored.
+    fn find(
+        &self,
+        entries: &FxHashMap<usize, *mut HeapInner<dyn Trace>>,
+        key: usize,
+    ) -> Option<usize> {
+        if entries.contains_key(&key) {
+            return Some(key);
+        }
+        if !self.heap.is_copying() {
+            return None;
+        }
+        // keys are rehashed at flip, until then entry might be stored under old address of the key.
+        entries.keys().copied().find(|entry| unsafe {
+            (*(*entry as *const HeapInner<u8>)).fwdptr() == Address::from(key)
+        })
+    }
+
+    /// Inserts entry, value is kept alive while `key` is reachable.
+    pub fn insert(&self, key: Handle<K>, value: Handle<V>) {
+        let key = address(key);
+        let mut entries = self.table.entries.lock();
+        let key = self.find(&entries, key).unwrap_or(key);
+        entries.insert(key, value.inner as *mut HeapInner<dyn Trace>);
+    }
+
+    /// Returns rooted value of `key` entry.
+    pub fn get(&self, key: Handle<K>) -> Option<Rooted<V>> {
+        let entries = self.table.entries.lock();
+        let value = entries.get(&self.find(&entries, address(key))?)?;
+        let handle = Handle {
+            inner: *value as *mut HeapInner<V>,
+        };
+        drop(entries);
+        Some(crate::threads::mt_root(handle))
+    }
+
+    pub fn contains_key(&self, key: Handle<K>) -> bool {
+        let entries = self.table.entries.lock();
+        self.find(&entries, address(key)).is_some()
+    }
+
+    /// Removes `key` entry, returns false if there was no such entry.
+    pub fn remove(&self, key: Handle<K>) -> bool {
+        let mut entries = self.table.entries.lock();
+        match self.find(&entries, address(key)) {
+            Some(address) => entries.remove(&address).is_some(),
+            None => false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.table.entries.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<K: Trace + 'static, V: Trace + 'static> Default for GcWeakMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Current address of `key` object.
+fn address<K: Trace>(key: Handle<K>) -> usize {
+    unsafe { read_barrier_impl(key.inner) as usize }
+}
+
+// Entries are traced by collector only while their keys are alive.
+impl<K: Trace, V: Trace> Traceable for GcWeakMap<K, V> {}
+impl<K: Trace, V: Trace> Finalizer for GcWeakMap<K, V> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::heap::testing::*;
+    use crate::threads::mt_alloc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    struct Entry(Option<Handle<usize>>, Arc<AtomicBool>);
+
+    impl Traceable for Entry {
+        fn trace_with(&self, tracer: &mut Tracer) {
+            self.0.trace_with(tracer);
+        }
+    }
+    impl Finalizer for Entry {
+        fn finalize(&mut self) {
+            self.1.store(true, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_weak_map() {
+        let heap = attached(generational(2));
+        let map = GcWeakMap::new();
+        let dead = (0..3)
+            .map(|_| Arc::new(AtomicBool::new(false)))
+            .collect::<Vec<_>>();
+        let key = mt_alloc(1usize, false);
+        let chained = mt_alloc(2usize, false);
+        let entry =
+            |next, dead: &Arc<AtomicBool>| Handle::from(mt_alloc(Entry(next, dead.clone()), true));
+        map.insert(
+            Handle::from(&key),
+            entry(Some(Handle::from(&chained)), &dead[0]),
+        );
+        // reachable only through value of the first entry.
+        map.insert(Handle::from(chained), entry(None, &dead[1]));
+        map.insert(Handle::from(mt_alloc(3usize, false)), entry(None, &dead[2]));
+        assert_eq!(map.len(), 3);
+
+        heap.minor_collect();
+        assert!(!dead[0].load(Ordering::Relaxed));
+        assert!(!dead[1].load(Ordering::Relaxed));
+        assert!(dead[2].load(Ordering::Relaxed));
+        assert_eq!(map.len(), 2);
+
+        heap.collect_sync();
+        assert!(!dead[0].load(Ordering::Relaxed));
+        assert!(!dead[1].load(Ordering::Relaxed));
+        assert_eq!(map.len(), 2);
+        assert!(map.get(Handle::from(&key)).unwrap().0.is_some());
+        assert!(map.remove(Handle::from(&key)));
+        assert!(!map.contains_key(Handle::from(&key)));
+
+        heap.collect_sync();
+        assert!(dead.iter().all(|dead| dead.load(Ordering::Relaxed)));
+        assert!(map.is_empty());
+        drop(map);
+        heap.collect_sync();
+        assert!(heap.ephemerons.lock().is_empty());
+        finish(&heap);
+    }
+}